@@ -6,10 +6,14 @@ use bevy::{
     reflect::{serde::TypedReflectDeserializer, ReflectFromPtr},
     remote::BrpRequest,
 };
+use bevy_remote_stream::{
+    stats::{ClientStats, StreamStats},
+    StreamClientId,
+};
 use serde::{de::DeserializeSeed, Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{DisabledComponents, InspectorContext};
+use crate::{filter::EntityFilter, DisabledComponents, InspectorContext, TrackedDatas};
 
 trait Execute {
     type Output: Serialize;
@@ -18,16 +22,36 @@ trait Execute {
         -> anyhow::Result<Self::Output>;
 }
 
-macro_rules! try_deserialize_req {
-    ($req:ident, $($method:literal, $kind:ident)*) => {
-        match $req.method.as_str() {
-            $(
-                $method => {
-                    let req = serde_json::from_value($req.params.ok_or(anyhow!("Missing param"))?)?;
-                    return Ok(Command::$kind(req));
-                },
-            )*
-            _ => Err(anyhow!("Unknown method")),
+macro_rules! impl_commands {
+    (
+        one_shot: $($method:literal, $kind:ident)*;
+        stream: $($stream_method:literal, $stream_kind:ident)*
+    ) => {
+        impl Command {
+            /// Method names of the commands that don't need a stream, also served as
+            /// [`OneShotMethods`](bevy_remote_stream::OneShotMethods). The other ones configure
+            /// the client's stream and require one.
+            pub const ONE_SHOT_METHODS: &'static [&'static str] = &[$($method),*];
+
+            pub fn try_from_brp(req: BrpRequest) -> anyhow::Result<Self> {
+                match req.method.as_str() {
+                    $(
+                        $method => {
+                            let params = req.params.unwrap_or_else(|| Value::Object(Default::default()));
+                            let req = serde_json::from_value(params)?;
+                            return Ok(Command::$kind(req));
+                        },
+                    )*
+                    $(
+                        $stream_method => {
+                            let params = req.params.unwrap_or_else(|| Value::Object(Default::default()));
+                            let req = serde_json::from_value(params)?;
+                            return Ok(Command::$stream_kind(req));
+                        },
+                    )*
+                    _ => Err(anyhow!("Unknown method")),
+                }
+            }
         }
     };
 }
@@ -42,19 +66,53 @@ pub enum Command {
     ToggleVisibity(ToggleVisibity),
     ReparentEntity(ReparentEntity),
     SpawnEntity(SpawnEntity),
+    UpdateResource(UpdateResource),
+    InsertResource(InsertResource),
+    RemoveResource(RemoveResource),
+    SetFilter(SetFilter),
+    WatchEntity(WatchEntity),
+    UnwatchEntity(UnwatchEntity),
+    WatchResource(WatchResource),
+    UnwatchResource(UnwatchResource),
+    Resync(Resync),
+    Stats(Stats),
 }
 
+impl_commands!(
+    one_shot:
+    "update_component", UpdateComponent
+    "toggle_component", ToggleComponent
+    "remove_component", RemoveComponent
+    "insert_component", InsertComponent
+    "despawn_entity", DespawnEntity
+    "toggle_visibility", ToggleVisibity
+    "reparent_entity", ReparentEntity
+    "spawn_entity", SpawnEntity
+    "update_resource", UpdateResource
+    "insert_resource", InsertResource
+    "remove_resource", RemoveResource
+    "inspector/stats", Stats;
+    stream:
+    "set_filter", SetFilter
+    "watch_entity", WatchEntity
+    "unwatch_entity", UnwatchEntity
+    "watch_resource", WatchResource
+    "unwatch_resource", UnwatchResource
+    "resync", Resync
+);
+
 impl Command {
-    pub fn try_from_brp(req: BrpRequest) -> anyhow::Result<Self> {
-        try_deserialize_req!(req,
-            "update_component", UpdateComponent
-            "toggle_component", ToggleComponent
-            "remove_component", RemoveComponent
-            "insert_component", InsertComponent
-            "despawn_entity", DespawnEntity
-            "toggle_visibility", ToggleVisibity
-            "reparent_entity", ReparentEntity
-            "spawn_entity", SpawnEntity
+    /// Whether the command mutates the world, as opposed to only configuring the stream.
+    pub fn is_mutating(&self) -> bool {
+        !matches!(
+            self,
+            Command::SetFilter(_)
+                | Command::WatchEntity(_)
+                | Command::UnwatchEntity(_)
+                | Command::WatchResource(_)
+                | Command::UnwatchResource(_)
+                | Command::Resync(_)
+                | Command::Stats(_)
         )
     }
 
@@ -72,6 +130,16 @@ impl Command {
             Command::ToggleVisibity(command) => command.execute(ctx, world).and_then(map_result),
             Command::ReparentEntity(command) => command.execute(ctx, world).and_then(map_result),
             Command::SpawnEntity(command) => command.execute(ctx, world).and_then(map_result),
+            Command::UpdateResource(command) => command.execute(ctx, world).and_then(map_result),
+            Command::InsertResource(command) => command.execute(ctx, world).and_then(map_result),
+            Command::RemoveResource(command) => command.execute(ctx, world).and_then(map_result),
+            Command::SetFilter(command) => command.execute(ctx, world).and_then(map_result),
+            Command::WatchEntity(command) => command.execute(ctx, world).and_then(map_result),
+            Command::UnwatchEntity(command) => command.execute(ctx, world).and_then(map_result),
+            Command::WatchResource(command) => command.execute(ctx, world).and_then(map_result),
+            Command::UnwatchResource(command) => command.execute(ctx, world).and_then(map_result),
+            Command::Resync(command) => command.execute(ctx, world).and_then(map_result),
+            Command::Stats(command) => command.execute(ctx, world).and_then(map_result),
         };
         result
     }
@@ -445,3 +513,373 @@ impl Execute for SpawnEntity {
         Ok(child)
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateResource {
+    pub resource: usize,
+    pub value: Value,
+}
+
+impl Execute for UpdateResource {
+    type Output = ();
+
+    fn execute(
+        self,
+        _ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        world.resource_scope(|world, registry: Mut<AppTypeRegistry>| {
+            let registry = registry.read();
+            let resource_id = ComponentId::new(self.resource);
+            let type_id = world
+                .components()
+                .get_info(resource_id)
+                .and_then(|info| info.type_id())
+                .ok_or(anyhow!("Resource not found"))?;
+            let registration = registry
+                .get(type_id)
+                .ok_or(anyhow!("Resource is not registered"))?;
+
+            let deserializer = TypedReflectDeserializer::new(registration, &registry);
+            let deserialized = deserializer.deserialize(self.value)?;
+
+            if let Some(reflect_resource) = registration.data::<ReflectResource>() {
+                let mut reflect_mut = reflect_resource
+                    .reflect_mut(world)
+                    .ok_or(anyhow!("Resource does not exist"))?;
+
+                reflect_mut.try_apply(deserialized.as_ref())?;
+
+                return Ok(());
+            }
+
+            let mut resource_ptr = world
+                .get_resource_mut_by_id(resource_id)
+                .ok_or(anyhow!("Resource does not exist"))?;
+
+            let reflect_from_ptr = registration
+                .data::<ReflectFromPtr>()
+                .ok_or(anyhow!("Resource does not implement ReflectFromPtr"))?;
+
+            assert_eq!(
+                reflect_from_ptr.type_id(),
+                type_id,
+                "Mismatch between Ptr's type_id and ReflectFromPtr's type_id",
+            );
+
+            let reflect_mut = unsafe { reflect_from_ptr.as_reflect_mut(resource_ptr.as_mut()) };
+
+            reflect_mut
+                .as_reflect_mut()
+                .try_apply(deserialized.as_ref())?;
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InsertResource {
+    resource: usize,
+    value: Value,
+}
+
+impl Execute for InsertResource {
+    type Output = ();
+
+    fn execute(
+        self,
+        _ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let resource_id = ComponentId::new(self.resource);
+
+        world.resource_scope(|world, registry: Mut<AppTypeRegistry>| {
+            let registry = registry.read();
+            let type_id = world
+                .components()
+                .get_info(resource_id)
+                .and_then(|info| info.type_id())
+                .ok_or(anyhow!("Resource not found"))?;
+            let registration = registry
+                .get(type_id)
+                .ok_or(anyhow!("Resource is not registered"))?;
+            let reflect_resource = registration
+                .data::<ReflectResource>()
+                .ok_or(anyhow!("Resource does not implement ReflectResource"))?;
+
+            let deserializer = TypedReflectDeserializer::new(registration, &registry);
+            let partial_reflect = deserializer.deserialize(self.value)?;
+
+            if world.get_resource_by_id(resource_id).is_some() {
+                bail!("Resource already exists")
+            }
+
+            reflect_resource.insert(world, partial_reflect.as_ref(), &registry);
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoveResource {
+    resource: usize,
+}
+
+impl Execute for RemoveResource {
+    type Output = ();
+
+    fn execute(
+        self,
+        _ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let resource_id = ComponentId::new(self.resource);
+
+        // Only reflected resources can be removed, the app and plugins' own internal
+        // resources are expected to stay
+        world.resource_scope(|world, registry: Mut<AppTypeRegistry>| {
+            let registry = registry.read();
+            let type_id = world
+                .components()
+                .get_info(resource_id)
+                .and_then(|info| info.type_id())
+                .ok_or(anyhow!("Resource not found"))?;
+            let reflect_resource = registry
+                .get_type_data::<ReflectResource>(type_id)
+                .ok_or(anyhow!("Resource does not implement ReflectResource"))?;
+
+            if world.get_resource_by_id(resource_id).is_none() {
+                bail!("Resource does not exist")
+            }
+
+            reflect_resource.remove(world);
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetFilter(EntityFilter);
+
+impl Execute for SetFilter {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        tracked.filter = self.0;
+        tracked.archetypes.clear_filter_matches();
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WatchEntity {
+    entity: Entity,
+}
+
+impl Execute for WatchEntity {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        if world.get_entity(self.entity).is_err() {
+            bail!("Entity {} does not exist", self.entity);
+        }
+
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        if tracked.watched.insert(self.entity) && tracked.lazy {
+            tracked.pending_values.insert(self.entity);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnwatchEntity {
+    entity: Entity,
+}
+
+impl Execute for UnwatchEntity {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        tracked.watched.remove(&self.entity);
+        tracked.pending_values.remove(&self.entity);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WatchResource {
+    resource: usize,
+}
+
+impl Execute for WatchResource {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let resource_id = ComponentId::new(self.resource);
+        if world.get_resource_by_id(resource_id).is_none() {
+            bail!("Resource does not exist");
+        }
+
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        if tracked.watched_resources.insert(resource_id) {
+            tracked.pending_resource_values.insert(resource_id);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnwatchResource {
+    resource: usize,
+}
+
+impl Execute for UnwatchResource {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let resource_id = ComponentId::new(self.resource);
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        tracked.watched_resources.remove(&resource_id);
+        tracked.pending_resource_values.remove(&resource_id);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Resync {
+    /// Only resend these entities instead of the whole state.
+    entities: Option<Vec<Entity>>,
+}
+
+impl Execute for Resync {
+    type Output = ();
+
+    fn execute(
+        self,
+        ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let mut tracked = world.resource_mut::<TrackedDatas>();
+        let tracked = tracked
+            .get_mut(&ctx.client_id)
+            .ok_or(anyhow!("Client is not connected"))?;
+
+        match self.entities {
+            Some(entities) => tracked.resync_entities.extend(entities),
+            None => tracked.reset(),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Stats {}
+
+#[derive(Serialize)]
+pub struct StatsOutput {
+    clients: Vec<ClientStatsOutput>,
+    /// Statistics of all the clients since the app started.
+    total: ClientStatsOutput,
+    /// Number of clients the inspector keeps state for.
+    tracked_clients: usize,
+}
+
+#[derive(Serialize)]
+pub struct ClientStatsOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<StreamClientId>,
+    messages_sent: u64,
+    bytes_sent: u64,
+    dropped_responses: u64,
+    handler_runs: u64,
+    handler_time_ms: f64,
+    serialization_time_ms: f64,
+}
+
+impl ClientStatsOutput {
+    fn new(client: Option<StreamClientId>, stats: ClientStats) -> Self {
+        Self {
+            client,
+            messages_sent: stats.messages_sent,
+            bytes_sent: stats.bytes_sent,
+            dropped_responses: stats.dropped_responses,
+            handler_runs: stats.handler_runs,
+            handler_time_ms: stats.handler_time.as_secs_f64() * 1000.,
+            serialization_time_ms: stats.serialization_time.as_secs_f64() * 1000.,
+        }
+    }
+}
+
+impl Execute for Stats {
+    type Output = StatsOutput;
+
+    fn execute(
+        self,
+        _ctx: &mut InspectorContext,
+        world: &mut World,
+    ) -> anyhow::Result<Self::Output> {
+        let stats = world.resource::<StreamStats>();
+
+        Ok(StatsOutput {
+            clients: stats
+                .clients()
+                .into_iter()
+                .map(|(client_id, stats)| ClientStatsOutput::new(Some(client_id), stats))
+                .collect(),
+            total: ClientStatsOutput::new(None, stats.total()),
+            tracked_clients: world.resource::<TrackedDatas>().len(),
+        })
+    }
+}
@@ -1,12 +1,11 @@
 use bevy::{
     ecs::component::{ComponentId, ComponentInfo},
     prelude::{EntityRef, World},
-    reflect::{serde::TypedReflectSerializer, ReflectFromPtr, TypeRegistry},
+    reflect::{ReflectFromPtr, TypeRegistry},
 };
 use serde::Serialize;
-use serde_json::Value;
 
-use crate::{InspectorEvent, TrackedData};
+use crate::{snapshot::SnapshotValue, InspectorEvent, TrackedData};
 
 impl TrackedData {
     pub fn track_components(
@@ -74,12 +73,12 @@ impl InspectorComponentInfo {
     }
 }
 
-pub fn serialize_component(
+pub fn snapshot_component(
     component_id: ComponentId,
     entity_ref: &EntityRef,
     type_registry: &TypeRegistry,
     component_info: &ComponentInfo,
-) -> Option<Value> {
+) -> Option<SnapshotValue> {
     let component_ptr = entity_ref.get_by_id(component_id).ok()?;
     let type_id = component_info.type_id()?;
 
@@ -93,9 +92,8 @@ pub fn serialize_component(
 
     let reflect = unsafe { reflect_from_ptr.as_reflect(component_ptr) };
 
-    let serializer = TypedReflectSerializer::new(reflect.as_partial_reflect(), &type_registry);
-
-    let ret = serde_json::to_value(serializer).ok();
-
-    ret
+    Some(SnapshotValue::new(
+        reflect.as_partial_reflect(),
+        type_registry,
+    ))
 }
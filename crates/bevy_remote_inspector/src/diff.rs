@@ -0,0 +1,330 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    ecs::{component::ComponentId, entity::EntityHashMap},
+    prelude::*,
+    utils::HashMap,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A JSON patch (RFC 6902) operation.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all(serialize = "snake_case"))]
+#[serde(tag = "op")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+pub enum ValueUpdate {
+    Unchanged,
+    Patch(Vec<PatchOperation>),
+    Full(Value),
+}
+
+/// Last component values sent to a client, used to compute diffs.
+///
+/// Shared with the task serializing the updates of the client.
+#[derive(Default, Clone)]
+pub struct SentValues(Arc<Mutex<EntityHashMap<HashMap<ComponentId, Value>>>>);
+
+impl SentValues {
+    pub fn insert(&self, entity: Entity, component_id: ComponentId, value: Value) {
+        let mut values = self.0.lock().unwrap();
+        values
+            .entry(entity)
+            .or_default()
+            .insert(component_id, value);
+    }
+
+    pub fn remove(&self, entity: Entity) {
+        self.0.lock().unwrap().remove(&entity);
+    }
+
+    pub fn remove_component(&self, entity: Entity, component_id: ComponentId) {
+        if let Some(values) = self.0.lock().unwrap().get_mut(&entity) {
+            values.remove(&component_id);
+        }
+    }
+
+    /// Remembers `value` as the last sent value and returns what should be sent to the client.
+    /// Falls back to the full value when the patch is not smaller.
+    pub fn update(&self, entity: Entity, component_id: ComponentId, value: Value) -> ValueUpdate {
+        let mut values = self.0.lock().unwrap();
+        let values = values.entry(entity).or_default();
+        let Some(previous) = values.get_mut(&component_id) else {
+            values.insert(component_id, value.clone());
+            return ValueUpdate::Full(value);
+        };
+
+        let patch = diff(previous, &value);
+        if patch.is_empty() {
+            return ValueUpdate::Unchanged;
+        }
+
+        let update = if serialized_len(&patch) < serialized_len(&value) {
+            ValueUpdate::Patch(patch)
+        } else {
+            ValueUpdate::Full(value.clone())
+        };
+
+        *previous = value;
+
+        update
+    }
+}
+
+pub fn diff(old: &Value, new: &Value) -> Vec<PatchOperation> {
+    let mut patch = vec![];
+    diff_at(&mut String::new(), old, new, &mut patch);
+
+    patch
+}
+
+fn diff_at(path: &mut String, old: &Value, new: &Value, patch: &mut Vec<PatchOperation>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                let len = path.len();
+                push_token(path, key);
+                patch.push(PatchOperation::Remove { path: path.clone() });
+                path.truncate(len);
+            }
+
+            for (key, new_value) in new {
+                let len = path.len();
+                push_token(path, key);
+                match old.get(key) {
+                    Some(old_value) => diff_at(path, old_value, new_value, patch),
+                    None => patch.push(PatchOperation::Add {
+                        path: path.clone(),
+                        value: new_value.clone(),
+                    }),
+                }
+                path.truncate(len);
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (index, (old_value, new_value)) in old.iter().zip(new).enumerate() {
+                let len = path.len();
+                push_token(path, &index.to_string());
+                diff_at(path, old_value, new_value, patch);
+                path.truncate(len);
+            }
+
+            for (index, new_value) in new.iter().enumerate().skip(old.len()) {
+                let len = path.len();
+                push_token(path, &index.to_string());
+                patch.push(PatchOperation::Add {
+                    path: path.clone(),
+                    value: new_value.clone(),
+                });
+                path.truncate(len);
+            }
+
+            // Remove from the back so the indices stay valid
+            for index in (new.len()..old.len()).rev() {
+                let len = path.len();
+                push_token(path, &index.to_string());
+                patch.push(PatchOperation::Remove { path: path.clone() });
+                path.truncate(len);
+            }
+        }
+        (old, new) if old != new => patch.push(PatchOperation::Replace {
+            path: path.clone(),
+            value: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// Appends a JSON pointer reference token to `path`.
+fn push_token(path: &mut String, token: &str) {
+    path.push('/');
+    for c in token.chars() {
+        match c {
+            '~' => path.push_str("~0"),
+            '/' => path.push_str("~1"),
+            c => path.push(c),
+        }
+    }
+}
+
+fn serialized_len(value: &impl Serialize) -> usize {
+    struct ByteCounter(usize);
+
+    impl io::Write for ByteCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = ByteCounter(0);
+    let _ = serde_json::to_writer(&mut counter, value);
+
+    counter.0
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Applies `patch` to `value` like a client would.
+    fn apply(mut value: Value, patch: &[PatchOperation]) -> Value {
+        for operation in patch {
+            let (path, new_value) = match operation {
+                PatchOperation::Add { path, value } | PatchOperation::Replace { path, value } => {
+                    (path, Some(value.clone()))
+                }
+                PatchOperation::Remove { path } => (path, None),
+            };
+
+            let mut tokens = path
+                .split('/')
+                .skip(1)
+                .map(|token| token.replace("~1", "/").replace("~0", "~"))
+                .collect::<Vec<_>>();
+            let Some(last) = tokens.pop() else {
+                value = new_value.unwrap();
+                continue;
+            };
+
+            let parent = tokens.iter().fold(&mut value, |value, token| match value {
+                Value::Object(object) => object.get_mut(token).unwrap(),
+                Value::Array(array) => &mut array[token.parse::<usize>().unwrap()],
+                _ => panic!("Invalid path {path}"),
+            });
+            match (parent, operation, new_value) {
+                (Value::Object(object), _, Some(new_value)) => {
+                    object.insert(last, new_value);
+                }
+                (Value::Object(object), _, None) => {
+                    object.remove(&last).unwrap();
+                }
+                (Value::Array(array), PatchOperation::Add { .. }, Some(new_value)) => {
+                    array.insert(last.parse().unwrap(), new_value);
+                }
+                (Value::Array(array), _, Some(new_value)) => {
+                    array[last.parse::<usize>().unwrap()] = new_value;
+                }
+                (Value::Array(array), _, None) => {
+                    array.remove(last.parse().unwrap());
+                }
+                _ => panic!("Invalid path {path}"),
+            }
+        }
+
+        value
+    }
+
+    fn assert_patch(old: Value, new: Value) -> Vec<PatchOperation> {
+        let patch = diff(&old, &new);
+        assert_eq!(apply(old, &patch), new);
+        patch
+    }
+
+    #[test]
+    fn nested_objects() {
+        let patch = assert_patch(
+            json!({ "a": { "b": 1, "c": { "d": true } }, "removed": 0 }),
+            json!({ "a": { "b": 2, "c": { "d": true, "e": [1] } }, "added": null }),
+        );
+        assert_eq!(patch.len(), 4);
+    }
+
+    #[test]
+    fn growing_arrays() {
+        assert_patch(json!([1, 2]), json!([0, 2, 3, 4]));
+        assert_patch(json!({ "a": [] }), json!({ "a": [[1], { "b": 2 }] }));
+    }
+
+    #[test]
+    fn shrinking_arrays() {
+        let patch = assert_patch(json!([1, 2, 3, 4]), json!([0, 2]));
+        assert_eq!(
+            patch,
+            vec![
+                PatchOperation::Replace {
+                    path: "/0".into(),
+                    value: json!(0),
+                },
+                PatchOperation::Remove { path: "/3".into() },
+                PatchOperation::Remove { path: "/2".into() },
+            ]
+        );
+        assert_patch(json!([[1, 2], [3]]), json!([[1]]));
+    }
+
+    #[test]
+    fn escaped_keys() {
+        let patch = assert_patch(json!({ "a/b~c": 1 }), json!({ "a/b~c": 2, "~1": 3 }));
+        assert_eq!(
+            patch,
+            vec![
+                PatchOperation::Replace {
+                    path: "/a~1b~0c".into(),
+                    value: json!(2),
+                },
+                PatchOperation::Add {
+                    path: "/~01".into(),
+                    value: json!(3),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn root_replacement() {
+        let patch = assert_patch(json!({ "a": 1 }), json!([1]));
+        assert_eq!(
+            patch,
+            vec![PatchOperation::Replace {
+                path: String::new(),
+                value: json!([1]),
+            }]
+        );
+        assert!(diff(&json!(1), &json!(1)).is_empty());
+    }
+
+    #[test]
+    fn update_sends_the_smallest_value() {
+        let sent_values = SentValues::default();
+        let (entity, component_id) = (Entity::from_raw(0), ComponentId::new(0));
+        let large = json!({ "name": "a long enough name", "values": [1, 2, 3, 4, 5, 6] });
+
+        assert!(matches!(
+            sent_values.update(entity, component_id, large.clone()),
+            ValueUpdate::Full(_)
+        ));
+        assert!(matches!(
+            sent_values.update(entity, component_id, large.clone()),
+            ValueUpdate::Unchanged
+        ));
+
+        let mut changed = large.clone();
+        changed["values"][0] = json!(0);
+        match sent_values.update(entity, component_id, changed.clone()) {
+            ValueUpdate::Patch(patch) => assert_eq!(apply(large, &patch), changed),
+            _ => panic!("Expected a patch"),
+        }
+
+        // Replacing every field is larger than the value itself
+        let replaced = json!({ "other": 1 });
+        match sent_values.update(entity, component_id, replaced.clone()) {
+            ValueUpdate::Full(value) => assert_eq!(value, replaced),
+            _ => panic!("Expected the full value"),
+        }
+    }
+}
@@ -1,13 +1,25 @@
+use std::{any::TypeId, cell::UnsafeCell};
+
 use bevy::{
+    ecs::{
+        archetype::{Archetype, ArchetypeEntity, ArchetypeGeneration, ArchetypeId},
+        component::{ComponentId, ComponentInfo, StorageType, Tick},
+        entity::EntityHashSet,
+        storage::ComponentSparseSet,
+    },
     prelude::*,
-    reflect::{serde::TypedReflectSerializer, TypeRegistry},
+    reflect::TypeRegistry,
+    utils::HashMap,
 };
-use serde::Serialize;
-use serde_json::Value;
+use serde::{ser::SerializeTuple, Serialize, Serializer};
 
 use crate::{
-    component::serialize_component, type_registry::ZeroSizedTypes, InspectorContext,
-    InspectorEvent, TrackedData,
+    component::snapshot_component,
+    diff::{PatchOperation, SentValues, ValueUpdate},
+    filter::EntityFilter,
+    snapshot::SnapshotValue,
+    type_registry::ZeroSizedTypes,
+    InspectorContext, InspectorEvent, TrackedData,
 };
 
 #[derive(Serialize)]
@@ -19,15 +31,109 @@ pub enum EntityMutation {
         // Both onAdd and onChange
         changes: Vec<EntityMutationChange>,
         removes: Vec<(usize, bool)>,
+        // Only when diffs are enabled
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        patches: Vec<EntityMutationPatch>,
     },
 }
 
+/// Serialized as `[component, disabled, value?]`.
+pub struct EntityMutationChange {
+    component: usize,
+    disabled: bool,
+    value: Option<SnapshotValue>,
+    /// Sent even without a value, e.g. when the component is added.
+    required: bool,
+    send: SendValue,
+}
+
+/// How a component value is sent once serialized.
+#[derive(Clone, Copy)]
+enum SendValue {
+    Full,
+    /// Send the full value and remember it to diff the next ones.
+    Record,
+    /// Send a patch against the last sent value when it is smaller.
+    Diff,
+}
+
+impl EntityMutationChange {
+    fn new(component_id: ComponentId, disabled: bool, value: Option<SnapshotValue>) -> Self {
+        Self {
+            component: component_id.index(),
+            disabled,
+            value,
+            required: true,
+            send: SendValue::Full,
+        }
+    }
+}
+
+impl Serialize for EntityMutationChange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2 + usize::from(self.value.is_some()))?;
+        tuple.serialize_element(&self.component)?;
+        tuple.serialize_element(&self.disabled)?;
+        if let Some(value) = &self.value {
+            tuple.serialize_element(value)?;
+        }
+        tuple.end()
+    }
+}
+
 #[derive(Serialize)]
-pub struct EntityMutationChange(
-    usize,
-    bool,
-    #[serde(skip_serializing_if = "Option::is_none")] Option<Value>,
-);
+pub struct EntityMutationPatch(usize, Vec<PatchOperation>);
+
+impl EntityMutation {
+    /// Serializes the component values of the mutation and diffs them against the values
+    /// last sent to the client. Returns `false` if there is nothing left to send.
+    pub fn serialize_values(
+        &mut self,
+        entity: Entity,
+        type_registry: &TypeRegistry,
+        sent_values: &SentValues,
+    ) -> bool {
+        let EntityMutation::Change {
+            changes,
+            removes,
+            patches,
+        } = self
+        else {
+            return true;
+        };
+
+        changes.retain_mut(|change| {
+            let component_id = ComponentId::new(change.component);
+            let value = change
+                .value
+                .take()
+                .and_then(|value| value.into_value(type_registry));
+            let value = match (value, change.send) {
+                (Some(value), SendValue::Record) => {
+                    sent_values.insert(entity, component_id, value.clone());
+                    Some(value)
+                }
+                (Some(value), SendValue::Diff) => {
+                    match sent_values.update(entity, component_id, value) {
+                        ValueUpdate::Unchanged => None,
+                        ValueUpdate::Patch(patch) => {
+                            patches.push(EntityMutationPatch(change.component, patch));
+                            None
+                        }
+                        ValueUpdate::Full(value) => Some(value),
+                    }
+                }
+                (value, _) => value,
+            };
+
+            change.value = value.map(SnapshotValue::Serialized);
+            // Only if the component is untracked or serializable
+            change.required || change.value.is_some()
+        });
+
+        !changes.is_empty() || !removes.is_empty() || !patches.is_empty()
+    }
+}
 
 impl TrackedData {
     pub fn track_entities(
@@ -37,6 +143,7 @@ impl TrackedData {
         type_registry: &TypeRegistry,
         ctx: &mut InspectorContext,
         zsts: &ZeroSizedTypes,
+        this_run: Tick,
     ) {
         let _ = ctx
             .disabled_components
@@ -52,142 +159,448 @@ impl TrackedData {
 
         for removed in removed_entities {
             ctx.on_entity_removed(removed.0);
+            self.watched.remove(&removed.0);
+            self.sent_values.remove(removed.0);
             events.push(InspectorEvent::Entity {
                 entity: removed.0,
                 mutation: EntityMutation::Remove,
             });
         }
 
-        let this_run = world.read_change_tick();
-        for entity_ref in world.iter_entities() {
-            let id = entity_ref.id();
-            let entity_disbled_components = ctx.disabled_components.get_mut(&entity_ref.id());
-            if let Some(component_ids) = self.entities.get_mut(&id) {
-                let mut changes: Vec<EntityMutationChange> = vec![];
-                let archetype = entity_ref.archetype();
-                let removed_component_ids = component_ids
-                    .extract_if(|id| {
-                        archetype
-                            .components()
-                            .find(|component_id| component_id == id)
-                            .is_none()
-                    })
-                    .map(|id| {
-                        let is_disabled = entity_disbled_components
-                            .as_ref()
-                            .map(|disabled| disabled.contains_key(&id))
-                            .unwrap_or_default();
+        for entity in self.resync_entities.drain() {
+            if self.entities.remove(&entity).is_some() {
+                self.sent_values.remove(entity);
+                self.pending_values.remove(&entity);
+                events.push(InspectorEvent::Entity {
+                    entity,
+                    mutation: EntityMutation::Remove,
+                });
+            }
+        }
 
-                        (id.index(), is_disabled)
-                    })
-                    .collect::<Vec<_>>();
+        let allowed_entities = self.filter.allowed_entities(world);
+        self.archetypes.update(world, zsts, &self.filter);
 
-                for component_id in entity_ref.archetype().components() {
-                    let Some(ticks) = entity_ref.get_change_ticks_by_id(component_id) else {
-                        continue;
-                    };
+        // Entities that no longer match the filter are only removed from the client's view
+        if !self.filter.is_empty() {
+            let filtered_entities = self
+                .entities
+                .keys()
+                .filter(|entity| {
+                    !self.matches_filter(
+                        world,
+                        allowed_entities.as_ref(),
+                        **entity,
+                        ctx.disabled_components.get(*entity),
+                    )
+                })
+                .copied()
+                .collect::<Vec<_>>();
 
-                    let Some(component_info) = world.components().get_info(component_id) else {
+            for filtered in filtered_entities {
+                self.entities.remove(&filtered);
+                self.sent_values.remove(filtered);
+                events.push(InspectorEvent::Entity {
+                    entity: filtered,
+                    mutation: EntityMutation::Remove,
+                });
+            }
+        }
+
+        let world = &*world;
+        let mut tracker = EntityTracker {
+            tracked: self,
+            events,
+            world,
+            type_registry,
+            ctx,
+            allowed_entities: allowed_entities.as_ref(),
+            this_run,
+        };
+
+        match &allowed_entities {
+            Some(allowed) => {
+                for entity in allowed {
+                    let Some(location) = world.entities().get(*entity) else {
                         continue;
                     };
-
-                    if !ticks.is_changed(world.last_change_tick(), this_run) {
+                    let archetype = &world.archetypes()[location.archetype_id];
+                    let columns = tracker.columns(archetype);
+                    let archetype_entity = &archetype.entities()[location.archetype_row.index()];
+                    tracker.track(archetype, &columns, archetype_entity);
+                }
+            }
+            None => {
+                for archetype in world.archetypes().iter() {
+                    // Entities of an archetype that doesn't match the filter can only match
+                    // through their disabled components
+                    let matches_filter = tracker.tracked.archetypes.matches_filter(archetype.id());
+                    if archetype.is_empty()
+                        || (!matches_filter && tracker.ctx.disabled_components.is_empty())
+                    {
                         continue;
                     }
 
+                    let columns = tracker.columns(archetype);
+                    for archetype_entity in archetype.entities() {
+                        if matches_filter
+                            || tracker
+                                .ctx
+                                .disabled_components
+                                .contains_key(&archetype_entity.id())
+                        {
+                            tracker.track(archetype, &columns, archetype_entity);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks an entity against the filter with the match cached for its archetype,
+    /// unless it has disabled components since they count as present.
+    fn matches_filter(
+        &self,
+        world: &World,
+        allowed_entities: Option<&EntityHashSet>,
+        entity: Entity,
+        disabled_components: Option<&HashMap<ComponentId, Box<dyn PartialReflect>>>,
+    ) -> bool {
+        if disabled_components.is_some() {
+            return self.filter.matches(
+                allowed_entities,
+                &world.entity(entity),
+                disabled_components,
+            );
+        }
+
+        allowed_entities.is_none_or(|allowed| allowed.contains(&entity))
+            && world
+                .entities()
+                .get(entity)
+                .is_some_and(|location| self.archetypes.matches_filter(location.archetype_id))
+    }
+}
+
+/// Components of the archetypes seen by a client and whether they match its filter,
+/// resolved once per archetype.
+pub struct TrackedArchetypes {
+    generation: ArchetypeGeneration,
+    archetypes: Vec<Box<[ArchetypeComponent]>>,
+    /// Indexed by archetype, cleared when the filter changes.
+    matches_filter: Vec<bool>,
+}
+
+impl Default for TrackedArchetypes {
+    fn default() -> Self {
+        Self {
+            generation: ArchetypeGeneration::initial(),
+            archetypes: vec![],
+            matches_filter: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ArchetypeComponent {
+    id: ComponentId,
+    is_zst: bool,
+    always_streamed: bool,
+}
+
+impl TrackedArchetypes {
+    /// Resolves the components of the archetypes created since the last update,
+    /// and matches the archetypes against `filter` if it changed.
+    fn update(&mut self, world: &World, zsts: &ZeroSizedTypes, filter: &EntityFilter) {
+        let archetypes = world.archetypes();
+        for archetype in &archetypes[self.generation..] {
+            let components = archetype
+                .components()
+                .filter_map(|component_id| {
+                    let component_info = world.components().get_info(component_id)?;
+                    Some(ArchetypeComponent {
+                        id: component_id,
+                        is_zst: component_info
+                            .type_id()
+                            .is_some_and(|type_id| zsts.contains_key(&type_id)),
+                        always_streamed: is_always_streamed(component_info),
+                    })
+                })
+                .collect();
+            self.archetypes.push(components);
+        }
+        self.generation = archetypes.generation();
+
+        let matched = self.matches_filter.len();
+        self.matches_filter.extend(
+            archetypes
+                .iter()
+                .skip(matched)
+                .map(|archetype| filter.matches_archetype(archetype)),
+        );
+    }
+
+    /// Must be called when the filter changes.
+    pub fn clear_filter_matches(&mut self) {
+        self.matches_filter.clear();
+    }
+
+    fn matches_filter(&self, archetype_id: ArchetypeId) -> bool {
+        self.matches_filter[archetype_id.index()]
+    }
+}
+
+/// A component of an archetype along with the storage of its change ticks.
+struct ArchetypeColumn<'w> {
+    component: ArchetypeComponent,
+    changed_ticks: Option<ChangedTicks<'w>>,
+}
+
+enum ChangedTicks<'w> {
+    Table(&'w [UnsafeCell<Tick>]),
+    SparseSet(&'w ComponentSparseSet),
+}
+
+impl ArchetypeColumn<'_> {
+    fn is_changed(&self, entity: &ArchetypeEntity, last_run: Tick, this_run: Tick) -> bool {
+        let tick = match &self.changed_ticks {
+            Some(ChangedTicks::Table(ticks)) => ticks.get(entity.table_row().as_usize()),
+            Some(ChangedTicks::SparseSet(sparse_set)) => sparse_set.get_changed_tick(entity.id()),
+            None => None,
+        };
+
+        // SAFETY: The world is borrowed immutably so the ticks can't be written to.
+        tick.is_some_and(|tick| unsafe { *tick.get() }.is_newer_than(last_run, this_run))
+    }
+}
+
+/// Tracks the entities of a client during an update.
+struct EntityTracker<'a, 'w, 'c> {
+    tracked: &'a mut TrackedData,
+    events: &'a mut Vec<InspectorEvent>,
+    world: &'w World,
+    type_registry: &'a TypeRegistry,
+    ctx: &'a mut InspectorContext<'c>,
+    allowed_entities: Option<&'a EntityHashSet>,
+    this_run: Tick,
+}
+
+impl<'w> EntityTracker<'_, 'w, '_> {
+    fn columns(&self, archetype: &Archetype) -> Vec<ArchetypeColumn<'w>> {
+        let storages = self.world.storages();
+        self.tracked.archetypes.archetypes[archetype.id().index()]
+            .iter()
+            .map(|component| {
+                let changed_ticks = match archetype.get_storage_type(component.id) {
+                    Some(StorageType::Table) => storages
+                        .tables
+                        .get(archetype.table_id())
+                        .and_then(|table| table.get_changed_ticks_slice_for(component.id))
+                        .map(ChangedTicks::Table),
+                    Some(StorageType::SparseSet) => storages
+                        .sparse_sets
+                        .get(component.id)
+                        .map(ChangedTicks::SparseSet),
+                    None => None,
+                };
+
+                ArchetypeColumn {
+                    component: *component,
+                    changed_ticks,
+                }
+            })
+            .collect()
+    }
+
+    fn track(
+        &mut self,
+        archetype: &Archetype,
+        columns: &[ArchetypeColumn],
+        archetype_entity: &ArchetypeEntity,
+    ) {
+        let id = archetype_entity.id();
+        let last_run = self.tracked.last_change_tick;
+        let tracked_archetype = self.tracked.entities.get(&id).copied();
+
+        // Components are only added or removed by moving the entity to another archetype
+        if tracked_archetype == Some(archetype.id())
+            && !self.tracked.pending_values.contains(&id)
+            && !columns
+                .iter()
+                .any(|column| column.is_changed(archetype_entity, last_run, self.this_run))
+        {
+            return;
+        }
+
+        let world = self.world;
+        let type_registry = self.type_registry;
+        let entity_ref = world.entity(id);
+        // Tracked entities that no longer match the filter are already removed
+        if tracked_archetype.is_none()
+            && !self.tracked.matches_filter(
+                world,
+                self.allowed_entities,
+                id,
+                self.ctx.disabled_components.get(&id),
+            )
+        {
+            return;
+        }
+
+        let tracked = &mut *self.tracked;
+        tracked.entities.insert(id, archetype.id());
+        let send_values = !tracked.lazy || tracked.watched.contains(&id);
+        let resend_values = tracked.pending_values.remove(&id);
+        let entity_disbled_components = self.ctx.disabled_components.get_mut(&id);
+        if let Some(tracked_archetype) = tracked_archetype {
+            let tracked_archetype = &world.archetypes()[tracked_archetype];
+            let mut changes: Vec<EntityMutationChange> = vec![];
+            let removed_component_ids = tracked_archetype
+                .components()
+                .filter(|component_id| !archetype.contains(*component_id))
+                .map(|component_id| {
+                    tracked.sent_values.remove_component(id, component_id);
                     let is_disabled = entity_disbled_components
                         .as_ref()
                         .map(|disabled| disabled.contains_key(&component_id))
                         .unwrap_or_default();
 
-                    let is_tracked = component_ids.contains(&component_id);
-                    if zsts.contains_key(&component_info.type_id().unwrap()) {
-                        // ZST are only serialized when they are added to the entity
-                        if !is_tracked {
-                            component_ids.insert(component_id);
-                            changes.push(EntityMutationChange(
-                                component_id.index(),
-                                is_disabled,
-                                None,
-                            ));
-                        }
-                    } else {
-                        let serialized = serialize_component(
-                            component_id,
-                            &entity_ref,
-                            &type_registry,
-                            component_info,
-                        );
-
-                        if !is_tracked {
-                            component_ids.insert(component_id);
-                        }
+                    (component_id.index(), is_disabled)
+                })
+                .collect::<Vec<_>>();
 
-                        if !is_tracked || serialized.is_some() {
-                            // Only if the component is untracked or serializable
-                            changes.push(EntityMutationChange(
-                                component_id.index(),
-                                is_disabled,
-                                serialized,
-                            ));
-                        }
-                    }
+            for column in columns {
+                if !resend_values && !column.is_changed(archetype_entity, last_run, self.this_run) {
+                    continue;
                 }
-                if changes.len() > 0 || removed_component_ids.len() > 0 {
-                    events.push(InspectorEvent::Entity {
-                        entity: id,
-                        mutation: EntityMutation::Change {
-                            changes,
-                            removes: removed_component_ids,
-                        },
-                    });
-                }
-            } else {
-                // Untracked entity, serialize all component
-                self.entities
-                    .insert(id, entity_ref.archetype().components().collect());
-                let disabled_componentsi = entity_disbled_components.map(|components| {
-                    let iter = components.iter().map(|(component_id, value)| {
-                        let serialized = {
-                            let reflect: &dyn PartialReflect = value.as_partial_reflect();
-                            let serializer = TypedReflectSerializer::new(reflect, &type_registry);
-
-                            let ret = serde_json::to_value(serializer).ok();
-
-                            ret
-                        };
-                        EntityMutationChange(component_id.index(), true, serialized)
-                    });
-
-                    return Box::new(iter) as Box<dyn Iterator<Item = EntityMutationChange>>;
-                });
 
-                let changes = entity_ref.archetype().components().map(|component_id| {
-                    let component_info = world.components().get_info(component_id).unwrap();
-                    let serialized = serialize_component(
-                        component_id,
-                        &entity_ref,
-                        &type_registry,
-                        component_info,
-                    );
+                let ArchetypeComponent {
+                    id: component_id,
+                    is_zst,
+                    always_streamed,
+                } = column.component;
 
-                    EntityMutationChange(component_id.index(), false, serialized)
-                });
+                let is_disabled = entity_disbled_components
+                    .as_ref()
+                    .map(|disabled| disabled.contains_key(&component_id))
+                    .unwrap_or_default();
 
-                let changes = if let Some(disabled_components) = disabled_componentsi {
-                    changes.chain(disabled_components).collect::<Vec<_>>()
+                let is_tracked = tracked_archetype.contains(component_id);
+                if is_zst {
+                    // ZST are only serialized when they are added to the entity
+                    if !is_tracked {
+                        changes.push(EntityMutationChange::new(component_id, is_disabled, None));
+                    }
                 } else {
-                    changes.collect()
-                };
+                    let value = if send_values || always_streamed {
+                        world
+                            .components()
+                            .get_info(component_id)
+                            .and_then(|component_info| {
+                                snapshot_component(
+                                    component_id,
+                                    &entity_ref,
+                                    type_registry,
+                                    component_info,
+                                )
+                            })
+                    } else {
+                        None
+                    };
 
-                events.push(InspectorEvent::Entity {
+                    // Whether the value is sent is decided once it is serialized
+                    if !is_tracked || value.is_some() {
+                        changes.push(EntityMutationChange {
+                            required: !is_tracked,
+                            send: if tracked.diff {
+                                SendValue::Diff
+                            } else {
+                                SendValue::Full
+                            },
+                            ..EntityMutationChange::new(component_id, is_disabled, value)
+                        });
+                    }
+                }
+            }
+
+            if resend_values {
+                // Disabled components are not in the archetype, send their values as well
+                if let Some(disabled_components) = entity_disbled_components.as_ref() {
+                    changes.extend(disabled_components.iter().map(|(component_id, value)| {
+                        EntityMutationChange::new(
+                            *component_id,
+                            true,
+                            Some(SnapshotValue::new(value.as_ref(), type_registry)),
+                        )
+                    }));
+                }
+            }
+
+            if changes.len() > 0 || removed_component_ids.len() > 0 {
+                self.events.push(InspectorEvent::Entity {
                     entity: id,
                     mutation: EntityMutation::Change {
                         changes,
-                        removes: vec![],
+                        removes: removed_component_ids,
+                        patches: vec![],
                     },
                 });
             }
+        } else {
+            // Untracked entity, serialize all component
+            let disabled_componentsi = entity_disbled_components.map(|components| {
+                let iter = components.iter().map(move |(component_id, value)| {
+                    let value = if send_values {
+                        Some(SnapshotValue::new(value.as_ref(), type_registry))
+                    } else {
+                        None
+                    };
+                    EntityMutationChange::new(*component_id, true, value)
+                });
+
+                return Box::new(iter) as Box<dyn Iterator<Item = EntityMutationChange>>;
+            });
+
+            let changes = archetype.components().map(|component_id| {
+                let component_info = world.components().get_info(component_id).unwrap();
+                let value = if send_values || is_always_streamed(component_info) {
+                    snapshot_component(component_id, &entity_ref, type_registry, component_info)
+                } else {
+                    None
+                };
+
+                EntityMutationChange {
+                    send: if tracked.diff {
+                        SendValue::Record
+                    } else {
+                        SendValue::Full
+                    },
+                    ..EntityMutationChange::new(component_id, false, value)
+                }
+            });
+
+            let changes = if let Some(disabled_components) = disabled_componentsi {
+                changes.chain(disabled_components).collect::<Vec<_>>()
+            } else {
+                changes.collect()
+            };
+
+            self.events.push(InspectorEvent::Entity {
+                entity: id,
+                mutation: EntityMutation::Change {
+                    changes,
+                    removes: vec![],
+                    patches: vec![],
+                },
+            });
         }
     }
 }
+
+/// Components whose values are streamed in lazy mode even if the entity is not watched,
+/// since the client needs them to build the entity tree.
+fn is_always_streamed(component_info: &ComponentInfo) -> bool {
+    component_info
+        .type_id()
+        .is_some_and(|type_id| type_id == TypeId::of::<Parent>() || type_id == TypeId::of::<Name>())
+}
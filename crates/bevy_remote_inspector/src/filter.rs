@@ -0,0 +1,96 @@
+use bevy::{
+    ecs::{archetype::Archetype, component::ComponentId, entity::EntityHashSet},
+    prelude::*,
+    utils::HashMap,
+};
+use serde::Deserialize;
+
+/// Limits which entities are tracked and streamed to a client.
+/// An empty filter matches every entity.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct EntityFilter {
+    /// Component ids an entity must have.
+    #[serde(default)]
+    with: Vec<usize>,
+    /// Component ids an entity must not have.
+    #[serde(default)]
+    without: Vec<usize>,
+    /// Only these entities are matched.
+    #[serde(default)]
+    entities: Option<Vec<Entity>>,
+    /// Only this entity and its descendants are matched.
+    #[serde(default)]
+    root: Option<Entity>,
+}
+
+impl EntityFilter {
+    /// Returns the set of entities allowed by `entities` and `root`,
+    /// or `None` if every entity is allowed.
+    pub fn allowed_entities(&self, world: &World) -> Option<EntityHashSet> {
+        let descendants = self.root.map(|root| {
+            let mut descendants = EntityHashSet::default();
+            if world.get_entity(root).is_err() {
+                return descendants;
+            }
+
+            let mut stack = vec![root];
+            while let Some(entity) = stack.pop() {
+                descendants.insert(entity);
+                if let Some(children) = world.get::<Children>(entity) {
+                    stack.extend(children.iter());
+                }
+            }
+
+            descendants
+        });
+
+        match (&self.entities, descendants) {
+            (Some(entities), Some(descendants)) => Some(
+                entities
+                    .iter()
+                    .filter(|entity| descendants.contains(*entity))
+                    .copied()
+                    .collect(),
+            ),
+            (Some(entities), None) => Some(entities.iter().copied().collect()),
+            (None, descendants) => descendants,
+        }
+    }
+
+    /// Returns `true` if the filter matches every entity.
+    pub fn is_empty(&self) -> bool {
+        self.with.is_empty()
+            && self.without.is_empty()
+            && self.entities.is_none()
+            && self.root.is_none()
+    }
+
+    /// Checks an entity against the filter, `allowed_entities` being the result of
+    /// [`EntityFilter::allowed_entities`]. Disabled components count as present.
+    pub fn matches(
+        &self,
+        allowed_entities: Option<&EntityHashSet>,
+        entity_ref: &EntityRef,
+        disabled_components: Option<&HashMap<ComponentId, Box<dyn PartialReflect>>>,
+    ) -> bool {
+        if allowed_entities.is_some_and(|allowed| !allowed.contains(&entity_ref.id())) {
+            return false;
+        }
+
+        self.matches_components(|component_id| {
+            entity_ref.archetype().contains(component_id)
+                || disabled_components.is_some_and(|disabled| disabled.contains_key(&component_id))
+        })
+    }
+
+    /// Checks the components of an archetype against `with` and `without`,
+    /// the result holds for its entities without disabled components.
+    pub fn matches_archetype(&self, archetype: &Archetype) -> bool {
+        self.matches_components(|component_id| archetype.contains(component_id))
+    }
+
+    fn matches_components(&self, has_component: impl Fn(ComponentId) -> bool) -> bool {
+        let has_component = |id: &usize| has_component(ComponentId::new(*id));
+        self.with.iter().all(has_component) && !self.without.iter().any(has_component)
+    }
+}
@@ -1,30 +1,80 @@
 mod command;
 mod component;
+mod diff;
 mod entity;
+mod filter;
+mod resource;
+mod snapshot;
 mod type_registry;
 
 use bevy::{
     app::PluginGroupBuilder,
-    ecs::{component::ComponentId, entity::EntityHashMap},
+    ecs::{
+        archetype::ArchetypeId,
+        component::{ComponentId, Tick},
+        entity::{EntityHashMap, EntityHashSet},
+    },
     prelude::*,
+    reflect::TypeRegistry,
     remote::{error_codes, BrpError, BrpResult},
-    utils::{HashMap, HashSet},
+    utils::{Duration, HashMap, HashSet},
 };
 use bevy_remote_stream::{
-    websocket::RemoteStreamWebSocketPlugin, OnDataHandlerInput, RemoteStreamHandlers,
-    RemoteStreamPlugin, StreamClientId, StreamHandlerInputRef, StreamMethods,
+    websocket::RemoteStreamWebSocketPlugin, ClientAccess, ClientAccesses, OnDataHandlerInput,
+    OneShotMethods, RemoteStreamHandlers, RemoteStreamPlugin, StreamClientId,
+    StreamHandlerInputRef, StreamMethods, StreamMinIntervals, StreamTasks,
 };
 use command::Command;
 use component::InspectorComponentInfo;
-use entity::EntityMutation;
-use serde::Serialize;
+use diff::SentValues;
+use entity::{EntityMutation, TrackedArchetypes};
+use filter::EntityFilter;
+use resource::ResourceMutation;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use type_registry::ZeroSizedTypes;
+use type_registry::{serialize_type_registry, ZeroSizedTypes};
 pub mod stream {
     pub use bevy_remote_stream::*;
 }
 
-pub struct RemoteInspectorPlugin;
+/// Error code returned when a read-only client sends a mutating command.
+pub const READ_ONLY_ERROR: i16 = -32001;
+
+/// What a client connected to the inspector is allowed to do, ordered from the least privileged.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Stream the world, mutating commands are rejected.
+    ReadOnly,
+    /// Stream the world and mutate it.
+    #[default]
+    ReadWrite,
+}
+
+impl From<ClientAccess> for Role {
+    fn from(access: ClientAccess) -> Self {
+        match access {
+            ClientAccess::ReadWrite => Role::ReadWrite,
+            ClientAccess::ReadOnly => Role::ReadOnly,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RemoteInspectorPlugin {
+    role: Role,
+}
+
+impl RemoteInspectorPlugin {
+    /// Reject mutating commands from every client.
+    /// Otherwise clients get the [`ClientAccess`] granted by their transport, e.g. from their token,
+    /// and can lower it with the `read_only` role.
+    #[must_use]
+    pub fn read_only(mut self) -> Self {
+        self.role = Role::ReadOnly;
+        self
+    }
+}
 
 impl Plugin for RemoteInspectorPlugin {
     fn build(&self, app: &mut App) {
@@ -32,6 +82,8 @@ impl Plugin for RemoteInspectorPlugin {
         let on_connect = app.main_mut().world_mut().register_system(on_connect);
         let on_disconnect = app.main_mut().world_mut().register_system(on_disconnect);
         let on_data = app.main_mut().world_mut().register_system(on_data);
+        let on_resync = app.main_mut().world_mut().register_system(on_resync);
+        let snapshot = app.main_mut().world_mut().register_system(snapshot);
         app.world_mut().resource_mut::<StreamMethods>().insert(
             "inspector/stream",
             RemoteStreamHandlers {
@@ -39,14 +91,38 @@ impl Plugin for RemoteInspectorPlugin {
                 on_disconnect: Some(on_disconnect),
                 on_connect: Some(on_connect),
                 on_data: Some(on_data),
+                on_resync: Some(on_resync),
+                min_interval: None,
             },
         );
+
+        let mut one_shot_methods = app.world_mut().resource_mut::<OneShotMethods>();
+        one_shot_methods.insert("inspector/snapshot", snapshot);
+        for method in Command::ONE_SHOT_METHODS {
+            one_shot_methods.insert(*method, on_data);
+        }
+
         app.init_resource::<TrackedDatas>()
             .init_resource::<DisabledComponents>()
-            .init_resource::<EntityVisibilities>();
+            .init_resource::<EntityVisibilities>()
+            .insert_resource(InspectorRole(self.role));
     }
 }
 
+/// The highest role granted to clients.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct InspectorRole(pub Role);
+
+/// The role granted to `client_id` by the inspector and its transport, clients can only lower it.
+fn granted_role(
+    inspector_role: &InspectorRole,
+    accesses: &ClientAccesses,
+    client_id: StreamClientId,
+) -> Role {
+    let access = accesses.get(client_id).map_or(Role::ReadWrite, Role::from);
+    inspector_role.0.min(access)
+}
+
 pub struct RemoteInspectorPlugins;
 
 impl PluginGroup for RemoteInspectorPlugins {
@@ -54,52 +130,145 @@ impl PluginGroup for RemoteInspectorPlugins {
         PluginGroupBuilder::start::<Self>()
             .add(RemoteStreamPlugin::default())
             .add(RemoteStreamWebSocketPlugin::default())
-            .add(RemoteInspectorPlugin)
+            .add(RemoteInspectorPlugin::default())
     }
 }
 
+/// Tracks the changes on the main thread, the values are serialized and diffed on the task pool.
 fn stream(
     InRef(input): StreamHandlerInputRef,
     world: &mut World,
-    mut events: Local<Vec<InspectorEvent>>,
     mut zsts: Local<ZeroSizedTypes>,
 ) -> Option<BrpResult> {
-    world.resource_scope(|world, mut tracked: Mut<TrackedDatas>| {
-        InspectorContext::run(world, |ctx, world| {
-            world.resource_scope(|world, type_registry: Mut<AppTypeRegistry>| {
-                let type_registry = type_registry.read();
-                let tracked = tracked.entry(input.client_id).or_default();
-                tracked.track_type_registry(&mut events, &mut zsts, &type_registry);
-                // let new_tables = world
-                //     .archetypes()
-                //     .iter()
-                //     .filter(|archetype| !tracked.tables.contains(&archetype.table_id().as_usize()))
-                //     .map(|archetype| archetype.table_id().as_usize())
-                //     .collect::<Vec<_>>();
-
-                // if !new_tables.is_empty() {
-                //     tracked.tables.extend_from_slice(new_tables.as_slice());
-                //     events.push(StreamEvent::NewTables { tables: new_tables });
-                // }
-
-                tracked.track_components(&mut events, world, &type_registry);
-                tracked.track_entities(&mut events, world, &type_registry, ctx, &zsts);
-            });
-        });
-    });
+    let mut events = vec![];
+    let sent_values = world.resource_scope(|world, mut tracked: Mut<TrackedDatas>| {
+        let tracked = tracked.entry(input.client_id).or_default();
+        track(world, tracked, input.client_id, &mut events, &mut zsts);
+        Some(tracked.sent_values.clone())
+    })?;
 
     if events.is_empty() {
         return None;
     }
 
-    let serialized = serde_json::to_value(&*events).unwrap();
+    let type_registry = world.resource::<AppTypeRegistry>().clone();
+    world
+        .resource_mut::<StreamTasks>()
+        .spawn(input.client_id, async move {
+            serialize_events(&mut events, &type_registry.read(), &sent_values);
+            if events.is_empty() {
+                return None;
+            }
+
+            Some(serde_json::to_value(&events).map_err(|e| BrpError {
+                code: error_codes::INTERNAL_ERROR,
+                message: e.to_string(),
+                data: None,
+            }))
+        });
+
+    None
+}
+
+/// Serializes the values captured by [`track`], dropping the events left with nothing to send.
+fn serialize_events(
+    events: &mut Vec<InspectorEvent>,
+    type_registry: &TypeRegistry,
+    sent_values: &SentValues,
+) {
+    events.retain_mut(|event| match event {
+        InspectorEvent::Entity { entity, mutation } => {
+            mutation.serialize_values(*entity, type_registry, sent_values)
+        }
+        InspectorEvent::Resource { mutation, .. } => mutation.serialize_values(type_registry),
+        InspectorEvent::TypeRegistry { types } => {
+            *types = serialize_type_registry(type_registry);
+            true
+        }
+        _ => true,
+    });
+}
+
+/// Pushes the events of everything that changed since the last update sent to the client.
+fn track(
+    world: &mut World,
+    tracked: &mut TrackedData,
+    client_id: StreamClientId,
+    events: &mut Vec<InspectorEvent>,
+    zsts: &mut ZeroSizedTypes,
+) {
+    InspectorContext::run(world, client_id, |ctx, world| {
+        world.resource_scope(|world, type_registry: Mut<AppTypeRegistry>| {
+            let type_registry = type_registry.read();
+            if std::mem::take(&mut tracked.reset) {
+                events.push(InspectorEvent::Reset);
+            }
+            tracked.track_type_registry(events, zsts, &type_registry);
+            // let new_tables = world
+            //     .archetypes()
+            //     .iter()
+            //     .filter(|archetype| !tracked.tables.contains(&archetype.table_id().as_usize()))
+            //     .map(|archetype| archetype.table_id().as_usize())
+            //     .collect::<Vec<_>>();
+
+            // if !new_tables.is_empty() {
+            //     tracked.tables.extend_from_slice(new_tables.as_slice());
+            //     events.push(StreamEvent::NewTables { tables: new_tables });
+            // }
+
+            // Every client compares the ticks to its own last update, the stream handler
+            // runs once per client so the world's last change tick can't be used
+            let this_run = world.change_tick();
+            tracked.check_change_tick(this_run);
+            tracked.track_components(events, world, &type_registry);
+            tracked.track_resources(events, world, &type_registry, zsts, this_run);
+            tracked.track_entities(events, world, &type_registry, ctx, zsts, this_run);
+            tracked.last_change_tick = this_run;
+        });
+    });
+}
+
+/// One-shot method returning the events of a full snapshot, filtered like a stream.
+fn snapshot(In((client_id, req)): OnDataHandlerInput, world: &mut World) -> Option<BrpResult> {
+    let params = match req
+        .params
+        .map(serde_json::from_value::<StreamParams>)
+        .transpose()
+    {
+        Ok(params) => params.unwrap_or_default(),
+        Err(e) => {
+            return Some(BrpResult::Err(BrpError {
+                code: error_codes::INVALID_PARAMS,
+                message: e.to_string(),
+                data: None,
+            }))
+        }
+    };
 
-    events.clear();
+    let mut tracked = TrackedData {
+        filter: params.filter,
+        lazy: params.lazy,
+        ..default()
+    };
+    let mut events = vec![];
+    track(
+        world,
+        &mut tracked,
+        client_id,
+        &mut events,
+        &mut ZeroSizedTypes::default(),
+    );
+    let type_registry = world.resource::<AppTypeRegistry>().read();
+    serialize_events(&mut events, &type_registry, &tracked.sent_values);
 
-    Some(BrpResult::Ok(serialized))
+    Some(serde_json::to_value(&events).map_err(|e| BrpError {
+        code: error_codes::INTERNAL_ERROR,
+        message: e.to_string(),
+        data: None,
+    }))
 }
 
-fn on_data(In((_, req)): OnDataHandlerInput, world: &mut World) -> Option<BrpResult> {
+fn on_data(In((client_id, req)): OnDataHandlerInput, world: &mut World) -> Option<BrpResult> {
     let command = match Command::try_from_brp(req) {
         Ok(r) => r,
         Err(e) => {
@@ -113,7 +282,29 @@ fn on_data(In((_, req)): OnDataHandlerInput, world: &mut World) -> Option<BrpRes
 
     trace!("New request: {:?}", command);
 
-    InspectorContext::run(world, |ctx, world| {
+    // Clients without a stream, e.g. plain HTTP requests, get the granted role
+    let role = world
+        .resource::<TrackedDatas>()
+        .get(&client_id)
+        .map_or_else(
+            || {
+                granted_role(
+                    world.resource::<InspectorRole>(),
+                    world.resource::<ClientAccesses>(),
+                    client_id,
+                )
+            },
+            |tracked| tracked.role,
+        );
+    if role == Role::ReadOnly && command.is_mutating() {
+        return Some(BrpResult::Err(BrpError {
+            code: READ_ONLY_ERROR,
+            message: "Client is read-only".into(),
+            data: None,
+        }));
+    }
+
+    InspectorContext::run(world, client_id, |ctx, world| {
         let result = command.execute(ctx, world);
 
         let result: Option<BrpResult> = match result {
@@ -134,20 +325,146 @@ fn on_disconnect(InRef(input): StreamHandlerInputRef, mut tracked: ResMut<Tracke
     debug!("Client {:?} disconnected", input.client_id);
 }
 
-fn on_connect(InRef(input): StreamHandlerInputRef) -> Option<BrpResult> {
+fn on_resync(InRef(input): StreamHandlerInputRef, mut tracked: ResMut<TrackedDatas>) {
+    if let Some(tracked) = tracked.get_mut(&input.client_id) {
+        tracked.reset();
+    }
+    debug!("Client {:?} resync", input.client_id);
+}
+
+fn on_connect(
+    InRef(input): StreamHandlerInputRef,
+    mut tracked: ResMut<TrackedDatas>,
+    mut min_intervals: ResMut<StreamMinIntervals>,
+    inspector_role: Res<InspectorRole>,
+    accesses: Res<ClientAccesses>,
+) -> Option<BrpResult> {
+    let granted = granted_role(&inspector_role, &accesses, input.client_id);
+    let params = match input
+        .params
+        .clone()
+        .map(serde_json::from_value::<StreamParams>)
+        .transpose()
+        .map_err(|e| e.to_string())
+        .and_then(|params| {
+            let params = params.unwrap_or_default();
+            match params.max_rate {
+                Some(max_rate) if max_rate <= 0. => Err("`max_rate` must be positive".into()),
+                Some(max_rate) if Duration::try_from_secs_f64(1. / max_rate).is_err() => {
+                    Err("`max_rate` is too small".into())
+                }
+                _ if params.role.is_some_and(|role| role > granted) => {
+                    Err("Client is read-only".into())
+                }
+                _ => Ok(params),
+            }
+        }) {
+        Ok(params) => params,
+        Err(e) => {
+            return Some(BrpResult::Err(BrpError {
+                code: error_codes::INVALID_PARAMS,
+                message: e,
+                data: None,
+            }))
+        }
+    };
+
+    // Changes are coalesced by the stream until the next update
+    if let Some(min_interval) = params
+        .max_rate
+        .and_then(|max_rate| Duration::try_from_secs_f64(1. / max_rate).ok())
+    {
+        min_intervals.insert(input.client_id, min_interval);
+    }
+
+    tracked.insert(
+        input.client_id,
+        TrackedData {
+            filter: params.filter,
+            lazy: params.lazy,
+            diff: params.diff,
+            role: params.role.unwrap_or(granted),
+            ..default()
+        },
+    );
+
     debug!("Client {:?} connected", input.client_id);
     None
 }
 
+/// Params of the `inspector/stream` connect request.
+#[derive(Deserialize, Default)]
+struct StreamParams {
+    #[serde(default)]
+    filter: EntityFilter,
+    /// Only stream component values of entities watched with `watch_entity`.
+    #[serde(default)]
+    lazy: bool,
+    /// Send JSON patches instead of full values for changed components.
+    #[serde(default)]
+    diff: bool,
+    /// Maximum number of updates per second sent to the client.
+    #[serde(default)]
+    max_rate: Option<f64>,
+    /// Role of the client, defaults to the role granted by the inspector and the transport.
+    /// It can only be lowered, requesting a higher role is an error.
+    #[serde(default)]
+    role: Option<Role>,
+}
+
 #[derive(Default)]
 struct TrackedData {
     type_registry: bool,
     components: HashSet<ComponentId>,
-    entities: EntityHashMap<HashSet<ComponentId>>,
-    // resources: HashSet<ComponentId>,
+    /// Archetype of each entity when it was last sent, its components are the ones the client knows.
+    entities: EntityHashMap<ArchetypeId>,
+    archetypes: TrackedArchetypes,
+    resources: HashSet<ComponentId>,
+    /// Resources whose values are streamed, the others are only streamed as added or removed.
+    watched_resources: HashSet<ComponentId>,
+    /// Watched resources whose value has not been sent yet.
+    pending_resource_values: HashSet<ComponentId>,
+    filter: EntityFilter,
+    lazy: bool,
+    watched: EntityHashSet,
+    /// Watched entities whose component values have not been sent yet.
+    pending_values: EntityHashSet,
+    diff: bool,
+    sent_values: SentValues,
+    /// Change tick of the last update sent to the client.
+    last_change_tick: Tick,
+    /// The client must discard its state before the next update.
+    reset: bool,
+    /// Entities to remove and resend on the next update.
+    resync_entities: EntityHashSet,
+    role: Role,
     // tables: Vec<usize>,
 }
 
+impl TrackedData {
+    /// Clamps the tick of the last update like the world does for component ticks,
+    /// so it stays valid if the client didn't update for a long time, e.g. while suspended.
+    fn check_change_tick(&mut self, this_run: Tick) {
+        if this_run.get().wrapping_sub(self.last_change_tick.get()) > Tick::MAX.get() {
+            self.last_change_tick = Tick::new(this_run.get().wrapping_sub(Tick::MAX.get()));
+        }
+    }
+
+    /// Forgets everything sent to the client so the next update sends a full snapshot.
+    fn reset(&mut self) {
+        *self = TrackedData {
+            filter: std::mem::take(&mut self.filter),
+            lazy: self.lazy,
+            watched: std::mem::take(&mut self.watched),
+            watched_resources: std::mem::take(&mut self.watched_resources),
+            diff: self.diff,
+            role: self.role,
+            reset: true,
+            ..default()
+        };
+    }
+}
+
 #[derive(Resource, Default, Deref, DerefMut)]
 struct TrackedDatas(HashMap<StreamClientId, TrackedData>);
 
@@ -155,7 +472,10 @@ struct TrackedDatas(HashMap<StreamClientId, TrackedData>);
 #[serde(rename_all(serialize = "snake_case"))]
 #[serde(tag = "kind")]
 enum InspectorEvent {
+    /// The client must discard its state, a full snapshot follows.
+    Reset,
     TypeRegistry {
+        /// Serialized by [`serialize_events`].
         types: Vec<Value>,
     },
     Component {
@@ -165,6 +485,10 @@ enum InspectorEvent {
         entity: Entity,
         mutation: EntityMutation,
     },
+    Resource {
+        resource: usize,
+        mutation: ResourceMutation,
+    },
     // NewTables {
     //     tables: Vec<usize>,
     // },
@@ -176,16 +500,22 @@ struct DisabledComponents(EntityHashMap<HashMap<ComponentId, Box<dyn PartialRefl
 struct EntityVisibilities(EntityHashMap<Visibility>);
 
 struct InspectorContext<'a> {
+    client_id: StreamClientId,
     disabled_components: &'a mut DisabledComponents,
     entity_visibilities: &'a mut EntityVisibilities,
 }
 
 impl<'a> InspectorContext<'a> {
-    fn run<T>(world: &mut World, f: impl FnOnce(&mut InspectorContext, &mut World) -> T) -> T {
+    fn run<T>(
+        world: &mut World,
+        client_id: StreamClientId,
+        f: impl FnOnce(&mut InspectorContext, &mut World) -> T,
+    ) -> T {
         world.resource_scope(|world, mut disabled_components: Mut<DisabledComponents>| {
             world.resource_scope(
                 |mut world, mut entity_visibilities: Mut<EntityVisibilities>| {
                     let mut ctx = InspectorContext {
+                        client_id,
                         disabled_components: &mut disabled_components,
                         entity_visibilities: &mut entity_visibilities,
                     };
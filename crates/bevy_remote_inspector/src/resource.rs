@@ -0,0 +1,143 @@
+use bevy::{
+    ecs::component::{ComponentInfo, Tick},
+    prelude::*,
+    ptr::Ptr,
+    reflect::{ReflectFromPtr, TypeRegistry},
+};
+use serde::Serialize;
+
+use crate::{snapshot::SnapshotValue, type_registry::ZeroSizedTypes, InspectorEvent, TrackedData};
+
+#[derive(Serialize)]
+#[serde(rename_all(serialize = "snake_case"))]
+#[serde(tag = "kind")]
+pub enum ResourceMutation {
+    Add {
+        name: String,
+        reflected: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<SnapshotValue>,
+    },
+    Change {
+        value: SnapshotValue,
+    },
+    Remove,
+}
+
+impl ResourceMutation {
+    /// Serializes the value of the mutation, returns `false` if the mutation should be dropped.
+    pub fn serialize_values(&mut self, type_registry: &TypeRegistry) -> bool {
+        match self {
+            ResourceMutation::Add { value, .. } => {
+                if let Some(snapshot) = value {
+                    if !snapshot.serialize_in_place(type_registry) {
+                        *value = None;
+                    }
+                }
+
+                true
+            }
+            ResourceMutation::Change { value } => value.serialize_in_place(type_registry),
+            ResourceMutation::Remove => true,
+        }
+    }
+}
+
+impl TrackedData {
+    pub fn track_resources(
+        &mut self,
+        events: &mut Vec<InspectorEvent>,
+        world: &World,
+        type_registry: &TypeRegistry,
+        zsts: &ZeroSizedTypes,
+        this_run: Tick,
+    ) {
+        let removed_resources = self
+            .resources
+            .extract_if(|id| world.get_resource_by_id(*id).is_none());
+
+        for removed in removed_resources {
+            self.watched_resources.remove(&removed);
+            self.pending_resource_values.remove(&removed);
+            events.push(InspectorEvent::Resource {
+                resource: removed.index(),
+                mutation: ResourceMutation::Remove,
+            });
+        }
+
+        for (info, ptr) in world.iter_resources() {
+            let resource_id = info.id();
+            let Some(ticks) = world.get_resource_change_ticks_by_id(resource_id) else {
+                continue;
+            };
+
+            // Values are only sent for watched resources, many resources change every frame
+            // (e.g. `Time`) and would keep the stream busy
+            let watched = self.watched_resources.contains(&resource_id);
+            let pending = self.pending_resource_values.remove(&resource_id);
+
+            if !self.resources.contains(&resource_id) {
+                self.resources.insert(resource_id);
+                let reflected = info
+                    .type_id()
+                    .is_some_and(|type_id| type_registry.get(type_id).is_some());
+
+                events.push(InspectorEvent::Resource {
+                    resource: resource_id.index(),
+                    mutation: ResourceMutation::Add {
+                        name: info.name().into(),
+                        reflected,
+                        value: watched
+                            .then(|| snapshot_resource(ptr, type_registry, info))
+                            .flatten(),
+                    },
+                });
+
+                continue;
+            }
+
+            let changed = watched && ticks.is_changed(self.last_change_tick, this_run);
+            if !pending && !changed {
+                continue;
+            }
+
+            if info
+                .type_id()
+                .is_some_and(|type_id| zsts.contains_key(&type_id))
+            {
+                // ZST resources have nothing to send on change
+                continue;
+            }
+
+            if let Some(value) = snapshot_resource(ptr, type_registry, info) {
+                events.push(InspectorEvent::Resource {
+                    resource: resource_id.index(),
+                    mutation: ResourceMutation::Change { value },
+                });
+            }
+        }
+    }
+}
+
+pub fn snapshot_resource(
+    resource_ptr: Ptr,
+    type_registry: &TypeRegistry,
+    resource_info: &ComponentInfo,
+) -> Option<SnapshotValue> {
+    let type_id = resource_info.type_id()?;
+
+    let reflect_from_ptr = type_registry.get_type_data::<ReflectFromPtr>(type_id)?;
+
+    assert_eq!(
+        reflect_from_ptr.type_id(),
+        type_id,
+        "Mismatch between Ptr's type_id and ReflectFromPtr's type_id",
+    );
+
+    let reflect = unsafe { reflect_from_ptr.as_reflect(resource_ptr) };
+
+    Some(SnapshotValue::new(
+        reflect.as_partial_reflect(),
+        type_registry,
+    ))
+}
@@ -0,0 +1,58 @@
+use bevy::reflect::{
+    serde::TypedReflectSerializer, PartialReflect, ReflectFromReflect, TypeRegistry,
+};
+use serde::{ser::Error, Serialize, Serializer};
+use serde_json::Value;
+
+/// A value cloned from the world during the frame, serialized later on the task pool.
+pub enum SnapshotValue {
+    Reflect(Box<dyn PartialReflect>),
+    Serialized(Value),
+}
+
+impl SnapshotValue {
+    /// Clones `value`, as its concrete type when possible so its custom serialization is kept.
+    pub fn new(value: &dyn PartialReflect, type_registry: &TypeRegistry) -> Self {
+        let concrete = value
+            .get_represented_type_info()
+            .and_then(|info| type_registry.get_type_data::<ReflectFromReflect>(info.type_id()))
+            .and_then(|from_reflect| from_reflect.from_reflect(value));
+
+        match concrete {
+            Some(concrete) => SnapshotValue::Reflect(concrete.into_partial_reflect()),
+            None => SnapshotValue::Reflect(value.clone_value()),
+        }
+    }
+
+    /// Serializes the value, returns `None` if it can't be serialized.
+    pub fn into_value(self, type_registry: &TypeRegistry) -> Option<Value> {
+        match self {
+            SnapshotValue::Reflect(value) => {
+                serde_json::to_value(TypedReflectSerializer::new(value.as_ref(), type_registry))
+                    .ok()
+            }
+            SnapshotValue::Serialized(value) => Some(value),
+        }
+    }
+
+    /// Serializes the value in place, returns `false` if it can't be serialized.
+    pub fn serialize_in_place(&mut self, type_registry: &TypeRegistry) -> bool {
+        let value = std::mem::replace(self, SnapshotValue::Serialized(Value::Null));
+        match value.into_value(type_registry) {
+            Some(value) => {
+                *self = SnapshotValue::Serialized(value);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Serialize for SnapshotValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SnapshotValue::Serialized(value) => value.serialize(serializer),
+            SnapshotValue::Reflect(_) => Err(S::Error::custom("Value was not serialized")),
+        }
+    }
+}
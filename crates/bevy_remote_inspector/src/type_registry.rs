@@ -27,12 +27,27 @@ impl TrackedData {
         }
 
         self.type_registry = true;
-        let types = serialize_type_registry(&type_registry, zsts);
-        events.push(InspectorEvent::TypeRegistry { types });
+        // Tracking needs the ZSTs right away, the types are serialized on the task pool
+        find_zsts(type_registry, zsts);
+        events.push(InspectorEvent::TypeRegistry { types: vec![] });
     }
 }
 
-fn serialize_type_registry(registry: &TypeRegistry, zsts: &mut ZeroSizedTypes) -> Vec<Value> {
+fn find_zsts(registry: &TypeRegistry, zsts: &mut ZeroSizedTypes) {
+    for registration in registry.iter() {
+        let field_len = match registration.type_info() {
+            TypeInfo::Struct(info) => info.field_len(),
+            TypeInfo::TupleStruct(info) => info.field_len(),
+            _ => continue,
+        };
+
+        if field_len == 0 {
+            zsts.insert(registration.type_id(), ());
+        }
+    }
+}
+
+pub fn serialize_type_registry(registry: &TypeRegistry) -> Vec<Value> {
     let types = registry
         .iter()
         .map(|registration| {
@@ -50,15 +65,9 @@ fn serialize_type_registry(registry: &TypeRegistry, zsts: &mut ZeroSizedTypes) -
             let type_name = registration.type_info().type_path();
             let type_info = match registration.type_info() {
                 TypeInfo::Struct(info) => {
-                    if info.field_len() == 0 {
-                        zsts.insert(info.type_id(), ());
-                    }
                     RegistryItem::Struct(StructValue::new(info, default_value))
                 }
                 TypeInfo::TupleStruct(info) => {
-                    if info.field_len() == 0 {
-                        zsts.insert(info.type_id(), ());
-                    }
                     RegistryItem::TupleStruct(TupleStructValue::new(info, default_value))
                 }
                 TypeInfo::Tuple(info) => RegistryItem::Tuple(TupleValue::new(info)),
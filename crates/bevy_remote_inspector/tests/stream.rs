@@ -0,0 +1,642 @@
+use bevy::{
+    prelude::*,
+    remote::{error_codes, BrpPayload},
+};
+use bevy_remote_inspector::{
+    stream::{
+        local::{request_one_shot, LocalStreamClient},
+        ClientAccess, ClientAccesses, RemoteStreamPlugin, StreamTasks,
+    },
+    RemoteInspectorPlugin, READ_ONLY_ERROR,
+};
+use serde_json::{json, Value};
+
+fn app(inspector: RemoteInspectorPlugin) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        TaskPoolPlugin::default(),
+        RemoteStreamPlugin::default(),
+        inspector,
+    ));
+    app
+}
+
+/// Runs the app until the stream updates serialized on the task pool are sent.
+fn update(app: &mut App) {
+    app.update();
+    while !app.world().resource::<StreamTasks>().is_empty() {
+        app.update();
+    }
+}
+
+fn connect(app: &App, params: Value) -> LocalStreamClient {
+    LocalStreamClient::connect(app.world(), "inspector/stream", Some(params)).unwrap()
+}
+
+/// Returns the events of all the stream updates received by `client`.
+fn events(client: &LocalStreamClient) -> Vec<Value> {
+    client
+        .drain()
+        .into_iter()
+        .filter(|response| response.id == Some(Value::from(0)))
+        .flat_map(|response| match response.payload {
+            BrpPayload::Result(Value::Array(events)) => events,
+            payload => panic!("Unexpected stream payload {payload:?}"),
+        })
+        .collect()
+}
+
+/// Returns the payload of the reply to the request `id` of `client`, dropping the other responses.
+fn reply(client: &LocalStreamClient, id: &Value) -> BrpPayload {
+    client
+        .drain()
+        .into_iter()
+        .find(|response| response.id.as_ref() == Some(id))
+        .unwrap()
+        .payload
+}
+
+fn entity_events(events: &[Value], entity: Entity) -> Vec<&Value> {
+    events
+        .iter()
+        .filter(|event| event["kind"] == "entity" && event["entity"] == json!(entity))
+        .collect()
+}
+
+#[test]
+fn streams_entity_changes() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    let client = connect(&app, json!({}));
+
+    update(&mut app);
+    let snapshot = events(&client);
+    assert!(snapshot
+        .iter()
+        .any(|event| event["kind"] == "type_registry"
+            && !event["types"].as_array().unwrap().is_empty()));
+    assert_eq!(entity_events(&snapshot, entity).len(), 1);
+
+    // Nothing changed
+    update(&mut app);
+    assert!(events(&client).is_empty());
+
+    app.world_mut().despawn(entity);
+    update(&mut app);
+    let events = events(&client);
+    let removed = entity_events(&events, entity);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0]["mutation"]["kind"], "remove");
+}
+
+#[derive(Component)]
+struct Marker;
+
+#[test]
+fn streams_component_removals() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let entity = app.world_mut().spawn((Name::new("first"), Marker)).id();
+    let other = app.world_mut().spawn(Name::new("other")).id();
+    let marker = app.world().component_id::<Marker>().unwrap();
+    let client = connect(&app, json!({}));
+
+    update(&mut app);
+    events(&client);
+
+    app.world_mut().entity_mut(entity).remove::<Marker>();
+    update(&mut app);
+    let events = events(&client);
+    let changed = entity_events(&events, entity);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        changed[0]["mutation"]["removes"],
+        json!([[marker.index(), false]])
+    );
+    assert!(entity_events(&events, other).is_empty());
+}
+
+#[test]
+fn clients_receive_each_change_once() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    app.register_type::<Name>();
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    let first = connect(&app, json!({}));
+
+    update(&mut app);
+    events(&first);
+
+    // The second client connects after the first one has seen the entity
+    app.world_mut()
+        .entity_mut(entity)
+        .insert(Name::new("second"));
+    let second = connect(&app, json!({}));
+    update(&mut app);
+    let first_events = events(&first);
+    let changed = entity_events(&first_events, entity);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0]["mutation"]["changes"][0][2], "second");
+    assert_eq!(entity_events(&events(&second), entity).len(), 1);
+
+    update(&mut app);
+    assert!(entity_events(&events(&first), entity).is_empty());
+    assert!(entity_events(&events(&second), entity).is_empty());
+
+    app.world_mut()
+        .entity_mut(entity)
+        .insert(Name::new("third"));
+    update(&mut app);
+    for client in [&first, &second] {
+        let events = events(client);
+        let changed = entity_events(&events, entity);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0]["mutation"]["changes"][0][2], "third");
+    }
+}
+
+#[test]
+fn failed_client_is_disconnected() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let failed = connect(&app, json!({}));
+    let mut client = connect(&app, json!({}));
+
+    update(&mut app);
+    events(&client);
+
+    // The stream is removed when its next update can't be sent
+    failed.close_receiver();
+    app.world_mut().spawn(Name::new("first"));
+    update(&mut app);
+
+    let id = client.request("inspector/stats", None).unwrap();
+    update(&mut app);
+    match reply(&client, &id) {
+        BrpPayload::Result(stats) => {
+            assert_eq!(stats["tracked_clients"], 1);
+            assert_eq!(stats["clients"].as_array().unwrap().len(), 1);
+        }
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+}
+
+#[test]
+fn read_only_client_cannot_mutate() {
+    let mut app = app(RemoteInspectorPlugin::default().read_only());
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    let mut client = connect(&app, json!({}));
+
+    update(&mut app);
+    events(&client);
+
+    let id = client
+        .request(
+            "despawn_entity",
+            Some(json!({ "entity": entity, "kind": "recursive" })),
+        )
+        .unwrap();
+    update(&mut app);
+
+    match reply(&client, &id) {
+        BrpPayload::Error(err) => assert_eq!(err.code, READ_ONLY_ERROR),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+    assert!(app.world().get_entity(entity).is_ok());
+}
+
+#[test]
+fn clients_cannot_raise_their_access() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    let rejected = LocalStreamClient::connect_with_access(
+        app.world(),
+        "inspector/stream",
+        Some(json!({ "role": "read_write" })),
+        ClientAccess::ReadOnly,
+    )
+    .unwrap();
+    let mut client = LocalStreamClient::connect_with_access(
+        app.world(),
+        "inspector/stream",
+        Some(json!({})),
+        ClientAccess::ReadOnly,
+    )
+    .unwrap();
+
+    update(&mut app);
+    match &rejected.drain()[0].payload {
+        BrpPayload::Error(err) => assert_eq!(err.code, error_codes::INVALID_PARAMS),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+    events(&client);
+
+    let id = client
+        .request(
+            "despawn_entity",
+            Some(json!({ "entity": entity, "kind": "recursive" })),
+        )
+        .unwrap();
+    update(&mut app);
+
+    match reply(&client, &id) {
+        BrpPayload::Error(err) => assert_eq!(err.code, READ_ONLY_ERROR),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+    assert!(app.world().get_entity(entity).is_ok());
+}
+
+#[test]
+fn one_shot_commands_respect_the_client_access() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    let despawn = |app: &App, access| {
+        request_one_shot(
+            app.world(),
+            "despawn_entity",
+            Some(json!({ "entity": entity, "kind": "recursive" })),
+            access,
+        )
+        .unwrap()
+    };
+
+    let rejected = despawn(&app, ClientAccess::ReadOnly);
+    let stats = request_one_shot(app.world(), "inspector/stats", None, ClientAccess::ReadOnly);
+    update(&mut app);
+    match rejected.try_recv().unwrap().payload {
+        BrpPayload::Error(err) => assert_eq!(err.code, READ_ONLY_ERROR),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+    assert!(matches!(
+        stats.unwrap().try_recv().unwrap().payload,
+        BrpPayload::Result(_)
+    ));
+    assert!(app.world().get_entity(entity).is_ok());
+
+    let accepted = despawn(&app, ClientAccess::ReadWrite);
+    update(&mut app);
+    assert!(matches!(
+        accepted.try_recv().unwrap().payload,
+        BrpPayload::Result(_)
+    ));
+    assert!(app.world().get_entity(entity).is_err());
+}
+
+#[test]
+fn stream_commands_are_not_one_shot_methods() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let response = request_one_shot(
+        app.world(),
+        "watch_entity",
+        Some(json!({ "entity": 0 })),
+        ClientAccess::ReadWrite,
+    )
+    .unwrap();
+    update(&mut app);
+
+    match response.try_recv().unwrap().payload {
+        BrpPayload::Error(err) => assert_eq!(err.code, error_codes::METHOD_NOT_FOUND),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+}
+
+#[test]
+fn replies_are_not_dropped_when_the_queue_is_full() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let mut client = connect(&app, json!({}));
+
+    // The client stops reading while the world keeps changing
+    for _ in 0..40 {
+        app.world_mut().spawn(Name::new("first"));
+        update(&mut app);
+    }
+
+    let ids = (0..4)
+        .map(|_| client.request("inspector/stats", None).unwrap())
+        .collect::<Vec<_>>();
+    update(&mut app);
+
+    let responses = client.drain();
+    for id in ids {
+        assert!(responses
+            .iter()
+            .any(|response| response.id == Some(id.clone())));
+    }
+}
+
+#[test]
+fn overflowed_client_is_resynced_once_drained() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let first = app.world_mut().spawn(Name::new("first")).id();
+    let client = connect(&app, json!({}));
+
+    // The client stops reading while the world keeps changing
+    for _ in 0..40 {
+        app.world_mut().spawn(Name::new("other"));
+        update(&mut app);
+    }
+    let last = app.world_mut().spawn(Name::new("last")).id();
+    client.drain();
+
+    update(&mut app);
+    let events = events(&client);
+    assert_eq!(events[0]["kind"], "reset");
+    assert!(events.iter().any(|event| event["kind"] == "type_registry"));
+    for entity in [first, last] {
+        assert_eq!(entity_events(&events, entity).len(), 1);
+    }
+}
+
+#[test]
+fn filter_changes_apply_to_existing_archetypes() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let marker = app.world_mut().register_component::<Marker>();
+    let marked = app.world_mut().spawn((Name::new("marked"), Marker)).id();
+    let unmarked = app.world_mut().spawn(Name::new("unmarked")).id();
+    let mut client = connect(&app, json!({ "filter": { "with": [marker.index()] } }));
+
+    update(&mut app);
+    let snapshot = events(&client);
+    assert_eq!(entity_events(&snapshot, marked).len(), 1);
+    assert!(entity_events(&snapshot, unmarked).is_empty());
+
+    client
+        .request("set_filter", Some(json!({ "without": [marker.index()] })))
+        .unwrap();
+    update(&mut app);
+    let events = events(&client);
+    let removed = entity_events(&events, marked);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0]["mutation"]["kind"], "remove");
+    assert_eq!(entity_events(&events, unmarked).len(), 1);
+}
+
+#[test]
+fn max_rate_limits_stream_updates() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let client = connect(&app, json!({ "max_rate": 0.001 }));
+
+    update(&mut app);
+    assert!(!events(&client).is_empty());
+
+    // The next update is due in 1000 seconds
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    update(&mut app);
+    assert!(entity_events(&events(&client), entity).is_empty());
+}
+
+#[test]
+fn tiny_max_rate_is_rejected() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let client = connect(&app, json!({ "max_rate": 1e-20 }));
+
+    update(&mut app);
+    match &client.drain()[0].payload {
+        BrpPayload::Error(err) => assert_eq!(err.code, error_codes::INVALID_PARAMS),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+}
+
+#[test]
+fn stats_record_bytes_received_by_local_clients() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let mut client = connect(&app, json!({}));
+
+    update(&mut app);
+    assert!(!events(&client).is_empty());
+
+    let id = client.request("inspector/stats", None).unwrap();
+    update(&mut app);
+    match reply(&client, &id) {
+        BrpPayload::Result(stats) => {
+            assert!(stats["clients"][0]["bytes_sent"].as_u64().unwrap() > 0);
+        }
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+}
+
+#[derive(Resource, Reflect, Default)]
+#[reflect(Resource)]
+struct Score(u32);
+
+fn resource_events(events: &[Value], resource: usize) -> Vec<&Value> {
+    events
+        .iter()
+        .filter(|event| event["kind"] == "resource" && event["resource"] == resource)
+        .collect()
+}
+
+#[test]
+fn streams_values_of_watched_resources() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    app.register_type::<Score>();
+    app.world_mut().insert_resource(Score(1));
+    let score = app.world().resource_id::<Score>().unwrap().index();
+    let mut client = connect(&app, json!({}));
+
+    update(&mut app);
+    let snapshot = events(&client);
+    let added = resource_events(&snapshot, score);
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0]["mutation"]["kind"], "add");
+    assert_eq!(added[0]["mutation"]["reflected"], true);
+    assert!(added[0]["mutation"].get("value").is_none());
+
+    // Unwatched resources are not sent when they change
+    app.world_mut().resource_mut::<Score>().0 = 2;
+    update(&mut app);
+    assert!(resource_events(&events(&client), score).is_empty());
+
+    client
+        .request("watch_resource", Some(json!({ "resource": score })))
+        .unwrap();
+    update(&mut app);
+    let events_after_watch = events(&client);
+    let changed = resource_events(&events_after_watch, score);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        changed[0]["mutation"],
+        json!({ "kind": "change", "value": 2 })
+    );
+
+    app.world_mut().resource_mut::<Score>().0 = 3;
+    update(&mut app);
+    let changed_events = events(&client);
+    let changed = resource_events(&changed_events, score);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0]["mutation"]["value"], 3);
+
+    client
+        .request("unwatch_resource", Some(json!({ "resource": score })))
+        .unwrap();
+    update(&mut app);
+    app.world_mut().resource_mut::<Score>().0 = 4;
+    update(&mut app);
+    assert!(resource_events(&events(&client), score).is_empty());
+
+    app.world_mut().remove_resource::<Score>();
+    update(&mut app);
+    let removed_events = events(&client);
+    let removed = resource_events(&removed_events, score);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0]["mutation"]["kind"], "remove");
+}
+
+#[test]
+fn resource_commands_apply_to_reflected_resources() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    app.register_type::<Score>();
+    app.world_mut().insert_resource(Score(1));
+    let score = app.world().resource_id::<Score>().unwrap().index();
+    let mut client = connect(&app, json!({}));
+    update(&mut app);
+    events(&client);
+
+    let id = client
+        .request(
+            "update_resource",
+            Some(json!({ "resource": score, "value": 2 })),
+        )
+        .unwrap();
+    update(&mut app);
+    assert!(matches!(reply(&client, &id), BrpPayload::Result(_)));
+    assert_eq!(app.world().resource::<Score>().0, 2);
+
+    let id = client
+        .request("remove_resource", Some(json!({ "resource": score })))
+        .unwrap();
+    update(&mut app);
+    assert!(matches!(reply(&client, &id), BrpPayload::Result(_)));
+    assert!(!app.world().contains_resource::<Score>());
+
+    let id = client
+        .request(
+            "insert_resource",
+            Some(json!({ "resource": score, "value": 3 })),
+        )
+        .unwrap();
+    update(&mut app);
+    assert!(matches!(reply(&client, &id), BrpPayload::Result(_)));
+    assert_eq!(app.world().resource::<Score>().0, 3);
+}
+
+#[test]
+fn internal_resources_cannot_be_removed() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let accesses = app.world().resource_id::<ClientAccesses>().unwrap().index();
+    let mut client = connect(&app, json!({}));
+    update(&mut app);
+    events(&client);
+
+    let id = client
+        .request("remove_resource", Some(json!({ "resource": accesses })))
+        .unwrap();
+    update(&mut app);
+    match reply(&client, &id) {
+        BrpPayload::Error(err) => assert_eq!(err.code, error_codes::INTERNAL_ERROR),
+        payload => panic!("Unexpected payload {payload:?}"),
+    }
+    assert!(app.world().contains_resource::<ClientAccesses>());
+
+    // The stream keeps running
+    let entity = app.world_mut().spawn(Name::new("first")).id();
+    update(&mut app);
+    assert_eq!(entity_events(&events(&client), entity).len(), 1);
+}
+
+#[derive(Component, Reflect)]
+#[reflect(Component)]
+struct Health(u32);
+
+#[test]
+fn lazy_clients_only_receive_values_of_watched_entities() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    app.register_type::<Health>();
+    let entity = app.world_mut().spawn(Health(1)).id();
+    let health = app.world().component_id::<Health>().unwrap().index();
+    let mut client = connect(&app, json!({ "lazy": true }));
+
+    // Membership is sent without values
+    update(&mut app);
+    let snapshot = events(&client);
+    let added = entity_events(&snapshot, entity);
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0]["mutation"]["changes"], json!([[health, false]]));
+
+    app.world_mut().get_mut::<Health>(entity).unwrap().0 = 2;
+    update(&mut app);
+    assert!(entity_events(&events(&client), entity).is_empty());
+
+    // Watching sends the current values on the next update, and every change after that
+    client
+        .request("watch_entity", Some(json!({ "entity": entity })))
+        .unwrap();
+    update(&mut app);
+    let watched = events(&client);
+    let changed = entity_events(&watched, entity);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        changed[0]["mutation"]["changes"],
+        json!([[health, false, 2]])
+    );
+
+    app.world_mut().get_mut::<Health>(entity).unwrap().0 = 3;
+    update(&mut app);
+    let watched = events(&client);
+    let changed = entity_events(&watched, entity);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        changed[0]["mutation"]["changes"],
+        json!([[health, false, 3]])
+    );
+
+    client
+        .request("unwatch_entity", Some(json!({ "entity": entity })))
+        .unwrap();
+    update(&mut app);
+    app.world_mut().get_mut::<Health>(entity).unwrap().0 = 4;
+    update(&mut app);
+    assert!(entity_events(&events(&client), entity).is_empty());
+}
+
+#[test]
+fn resync_resends_the_given_entities() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    app.register_type::<Name>();
+    let resynced = app.world_mut().spawn(Name::new("resynced")).id();
+    let other = app.world_mut().spawn(Name::new("other")).id();
+    let mut client = connect(&app, json!({}));
+    update(&mut app);
+    events(&client);
+
+    client
+        .request("resync", Some(json!({ "entities": [resynced] })))
+        .unwrap();
+    update(&mut app);
+    let events = events(&client);
+    assert!(!events.iter().any(|event| event["kind"] == "reset"));
+    let resent = entity_events(&events, resynced);
+    assert_eq!(resent.len(), 2);
+    assert_eq!(resent[0]["mutation"]["kind"], "remove");
+    assert_eq!(resent[1]["mutation"]["kind"], "change");
+    assert_eq!(resent[1]["mutation"]["changes"][0][2], "resynced");
+    assert!(entity_events(&events, other).is_empty());
+}
+
+#[test]
+fn resync_without_entities_resends_everything() {
+    let mut app = app(RemoteInspectorPlugin::default());
+    let first = app.world_mut().spawn(Name::new("first")).id();
+    let second = app.world_mut().spawn(Name::new("second")).id();
+    let mut client = connect(&app, json!({}));
+    update(&mut app);
+    events(&client);
+
+    client.request("resync", None).unwrap();
+    update(&mut app);
+    let events = events(&client);
+    assert_eq!(events[0]["kind"], "reset");
+    assert!(events.iter().any(|event| event["kind"] == "type_registry"));
+    for entity in [first, second] {
+        let resent = entity_events(&events, entity);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0]["mutation"]["kind"], "change");
+    }
+}
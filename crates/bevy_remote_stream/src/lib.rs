@@ -1,22 +1,45 @@
+pub mod local;
+#[cfg(feature = "websocket")]
+pub mod mux;
+#[cfg(any(feature = "websocket", all(unix, feature = "unix")))]
+pub mod server;
+pub mod stats;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(all(unix, feature = "unix"))]
+pub mod unix;
 #[cfg(feature = "websocket")]
 pub mod websocket;
 
-use std::sync::RwLock;
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        RwLock,
+    },
+};
 
 use bevy::{
+    diagnostic::{Diagnostic, RegisterDiagnostic},
     ecs::system::SystemId,
     prelude::*,
     remote::{error_codes, BrpError, BrpRequest, BrpResponse, BrpResult},
-    utils::HashMap,
+    tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+    utils::{Duration, HashMap, Instant},
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use smol::channel::{self, Receiver, Sender};
+use serde_json::{json, Value};
+use smol::channel::{self, Receiver, Sender, TrySendError};
+use stats::StreamStats;
 
 const CHANNEL_SIZE: usize = 16;
+/// Queue slots stream responses leave free for replies to the client's requests.
+const REPLY_SLOTS: usize = 8;
 
 pub struct RemoteStreamPlugin {
     methods: RwLock<Vec<(String, RemoteStreamHandlersBuilder)>>,
+    session_grace_period: Option<Duration>,
 }
 
 impl RemoteStreamPlugin {
@@ -36,12 +59,20 @@ impl RemoteStreamPlugin {
             .push((name.into(), handlers));
         self
     }
+
+    /// Let clients resume their stream after losing the connection, see [`SessionGracePeriod`].
+    #[must_use]
+    pub fn with_session_resume(mut self, grace_period: Duration) -> Self {
+        self.session_grace_period = Some(grace_period);
+        self
+    }
 }
 
 impl Default for RemoteStreamPlugin {
     fn default() -> Self {
         Self {
             methods: RwLock::new(vec![]),
+            session_grace_period: None,
         }
     }
 }
@@ -69,15 +100,36 @@ impl Plugin for RemoteStreamPlugin {
                     on_data: systems
                         .on_data
                         .map(|sys| app.main_mut().world_mut().register_boxed_system(sys)),
+                    on_resync: systems
+                        .on_resync
+                        .map(|sys| app.main_mut().world_mut().register_boxed_system(sys)),
+                    min_interval: systems.min_interval,
                 },
             );
         }
 
+        // The channel is created right away so clients can connect before the app runs
+        let (sender, receiver) = channel::bounded(CHANNEL_SIZE);
+
         app.insert_resource(stream_methods)
+            .init_resource::<OneShotMethods>()
+            .init_resource::<StreamMinIntervals>()
+            .init_resource::<ClientAccesses>()
+            .insert_resource(SessionGracePeriod(self.session_grace_period))
+            .insert_resource(StreamSender(sender))
+            .insert_resource(StreamReceiver(receiver))
             .init_resource::<ActiveStreams>()
-            .add_systems(PreStartup, setup_channel)
+            .init_resource::<StreamTasks>()
+            .init_resource::<StreamStats>()
+            .register_diagnostic(Diagnostic::new(stats::CLIENTS))
+            .register_diagnostic(Diagnostic::new(stats::MESSAGES_SENT))
+            .register_diagnostic(Diagnostic::new(stats::BYTES_SENT))
+            .register_diagnostic(Diagnostic::new(stats::DROPPED_RESPONSES))
+            .register_diagnostic(Diagnostic::new(stats::HANDLER_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(stats::SERIALIZATION_TIME).with_suffix("ms"))
             .add_systems(Update, process_remote_requests)
-            .add_systems(Update, on_app_exit.run_if(on_event::<AppExit>));
+            .add_systems(Update, on_app_exit.run_if(on_event::<AppExit>))
+            .add_systems(Last, stats::measure_diagnostics);
     }
 }
 
@@ -87,6 +139,13 @@ pub struct RemoteStreamHandlers {
     pub on_disconnect: Option<SystemId<StreamHandlerInputRef<'static>>>,
     pub on_data: Option<OnDataHandler>,
     pub update: StreamHandler,
+    /// Called once the client's response queue has drained after responses were dropped.
+    /// The handler should make the next `update` resend the full state to the client.
+    pub on_resync: Option<SystemId<StreamHandlerInputRef<'static>>>,
+    /// Minimum time between two runs of `update` for a client, see [`StreamMinIntervals`]
+    /// to choose it per client.
+    /// Skipped frames are not replayed, the handler is expected to coalesce changes.
+    pub min_interval: Option<Duration>,
 }
 
 pub struct StreamHandlerInput {
@@ -106,6 +165,8 @@ pub struct RemoteStreamHandlersBuilder {
     on_disconnect: Option<Box<dyn System<In = StreamHandlerInputRef<'static>, Out = ()>>>,
     on_data: Option<Box<dyn System<In = OnDataHandlerInput, Out = Option<BrpResult>>>>,
     update: Box<dyn System<In = StreamHandlerInputRef<'static>, Out = Option<BrpResult>>>,
+    on_resync: Option<Box<dyn System<In = StreamHandlerInputRef<'static>, Out = ()>>>,
+    min_interval: Option<Duration>,
 }
 
 impl RemoteStreamHandlersBuilder {
@@ -117,6 +178,8 @@ impl RemoteStreamHandlersBuilder {
             on_disconnect: None,
             on_data: None,
             update: Box::new(IntoSystem::into_system(update)),
+            on_resync: None,
+            min_interval: None,
         }
     }
 
@@ -135,6 +198,20 @@ impl RemoteStreamHandlersBuilder {
         self.on_disconnect = Some(Box::new(IntoSystem::into_system(system)));
         self
     }
+
+    pub fn on_resync<M>(
+        mut self,
+        system: impl IntoSystem<StreamHandlerInputRef<'static>, (), M>,
+    ) -> Self {
+        self.on_resync = Some(Box::new(IntoSystem::into_system(system)));
+        self
+    }
+
+    /// Limit how often the update handler runs for each client.
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = Some(min_interval);
+        self
+    }
 }
 
 /// Holds all implementations of methods known to the server.
@@ -154,6 +231,71 @@ impl StreamMethods {
     }
 }
 
+/// Methods answering a single request without a stream, e.g. plain HTTP requests.
+#[derive(Debug, Resource, Default)]
+pub struct OneShotMethods(HashMap<String, OnDataHandler>);
+
+impl OneShotMethods {
+    /// Adds a new method, replacing any existing method with that name.
+    ///
+    /// If there was an existing method with that name, returns its handler.
+    pub fn insert(
+        &mut self,
+        method_name: impl Into<String>,
+        handler: OnDataHandler,
+    ) -> Option<OnDataHandler> {
+        self.0.insert(method_name.into(), handler)
+    }
+}
+
+/// Minimum time between two runs of `update` for a client, overriding
+/// [`RemoteStreamHandlers::min_interval`].
+///
+/// Meant to be set by `on_connect` from the client's params, the interval is taken
+/// when the stream is opened.
+#[derive(Resource, Default)]
+pub struct StreamMinIntervals(HashMap<StreamClientId, Duration>);
+
+impl StreamMinIntervals {
+    /// Sets the interval of the stream being opened by `client_id`.
+    pub fn insert(&mut self, client_id: StreamClientId, min_interval: Duration) {
+        self.0.insert(client_id, min_interval);
+    }
+}
+
+/// What a client is allowed to do, granted by its transport, e.g. from the token it provided.
+///
+/// Handlers are responsible for rejecting what a [`ClientAccess::ReadOnly`] client may not do.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAccess {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+}
+
+/// The [`ClientAccess`] of the clients with an open stream or a pending one-shot request.
+#[derive(Resource, Default)]
+pub struct ClientAccesses(HashMap<StreamClientId, ClientAccess>);
+
+impl ClientAccesses {
+    /// Returns the access of `client_id`, `None` if it is not connected.
+    ///
+    /// Streams keep the client id they were opened with, including after being resumed.
+    pub fn get(&self, client_id: StreamClientId) -> Option<ClientAccess> {
+        self.0.get(&client_id).copied()
+    }
+}
+
+/// How long a stream is kept after its connection was lost, `None` if streams can't be resumed.
+///
+/// When set, the first response to a stream request is `{ "session": <token>, "resumed": false }`.
+/// A client reconnecting with the token within the grace period gets back its stream
+/// and `"resumed": true` instead of starting over.
+/// Responses are then numbered per connection by the transport, a client resuming with the
+/// number of the last response it received is resynced if it missed any.
+#[derive(Debug, Resource, Reflect)]
+pub struct SessionGracePeriod(pub Option<Duration>);
+
 #[derive(Resource, Deref, DerefMut)]
 pub struct StreamSender(Sender<StreamMessage>);
 
@@ -177,12 +319,71 @@ pub struct BrpStreamMessage {
     ///
     /// The value sent here is serialized and sent back to the client.
     pub sender: Sender<BrpResponse>,
+
+    /// The session of the stream to resume, see [`SessionGracePeriod`].
+    pub session: Option<SessionResume>,
+
+    /// What the client is allowed to do.
+    /// A stream is only resumed by a client with the same access it was opened with.
+    pub access: ClientAccess,
+}
+
+/// A request to resume the stream of a session, see [`SessionGracePeriod`].
+#[derive(Debug, Clone)]
+pub struct SessionResume {
+    /// The session token sent when the stream was opened.
+    pub token: String,
+    /// The number of the last response received on the previous connection.
+    /// The stream is resynced if it doesn't match the number of responses sent.
+    pub last_seq: Option<u64>,
 }
 
 pub enum StreamMessageKind {
     Connect(Option<Value>, BrpStreamMessage),
     Disconnect,
+    /// The connection was lost without the client disconnecting,
+    /// the stream is kept for the [`SessionGracePeriod`] if it has a session.
+    ConnectionLost,
     Data(Value),
+    /// A request to a [`OneShotMethods`] method, answered once on the given channel.
+    Request(BrpRequest, ClientAccess, Sender<BrpResponse>),
+}
+
+/// Stream updates computed on a task pool, e.g. to serialize them off the main thread.
+///
+/// An update handler spawns a task for its client instead of returning the result.
+/// The result is sent to the client once the task is done, and the update handler
+/// is not run for the client until then.
+#[derive(Resource, Default)]
+pub struct StreamTasks(HashMap<StreamClientId, Task<(Option<BrpResult>, Duration)>>);
+
+impl StreamTasks {
+    /// Runs `update` on the [`AsyncComputeTaskPool`] and sends its result to the client
+    /// once it is done. The time it took is recorded as serialization time in [`StreamStats`].
+    ///
+    /// Replaces the pending task of the client, if any.
+    pub fn spawn(
+        &mut self,
+        client_id: StreamClientId,
+        update: impl Future<Output = Option<BrpResult>> + Send + 'static,
+    ) {
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let start = Instant::now();
+            let result = update.await;
+            (result, start.elapsed())
+        });
+        self.0.insert(client_id, task);
+    }
+
+    /// Returns `true` if the client has a pending task.
+    pub fn contains(&self, client_id: StreamClientId) -> bool {
+        self.0.contains_key(&client_id)
+    }
+
+    /// Returns `true` if no client has a pending task.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Resource, Deref, DerefMut, Default)]
@@ -190,37 +391,145 @@ struct ActiveStreams(HashMap<StreamClientId, ActiveStream>);
 
 struct ActiveStream {
     request_id: Option<Value>,
+    method: String,
     sender: ActiveStreamSender,
     input: StreamHandlerInput,
     on_update: StreamHandler,
     on_disconnect: Option<SystemId<StreamHandlerInputRef<'static>>>,
     on_data: Option<OnDataHandler>,
+    on_resync: Option<SystemId<StreamHandlerInputRef<'static>>>,
+    min_interval: Option<Duration>,
+    last_update: Option<Instant>,
+    session: Option<String>,
+    suspended: Option<Suspended>,
 }
 
-struct ActiveStreamSender(Sender<BrpResponse>);
+/// A stream waiting for its client to reconnect.
+struct Suspended {
+    until: Instant,
+}
+
+impl ActiveStream {
+    fn suspend(&mut self, grace_period: Duration) {
+        // Nothing is sent to the lost connection anymore
+        self.sender.sender.close();
+        self.suspended = Some(Suspended {
+            until: Instant::now() + grace_period,
+        });
+    }
+
+    fn is_update_due(&mut self) -> bool {
+        let now = Instant::now();
+        match (self.min_interval, self.last_update) {
+            (Some(min_interval), Some(last_update))
+                if now.duration_since(last_update) < min_interval =>
+            {
+                false
+            }
+            _ => {
+                self.last_update = Some(now);
+                true
+            }
+        }
+    }
+}
+
+struct ActiveStreamSender {
+    sender: Sender<BrpResponse>,
+    /// Set when a response was dropped because the queue was full.
+    overflowed: AtomicBool,
+    /// Number of responses queued, the `seq` of the last one sent by the transport.
+    sent: AtomicU64,
+    client_id: StreamClientId,
+    stats: StreamStats,
+}
 
 impl ActiveStreamSender {
+    fn new(sender: Sender<BrpResponse>, client_id: StreamClientId, stats: StreamStats) -> Self {
+        Self {
+            sender,
+            overflowed: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            client_id,
+            stats,
+        }
+    }
+
+    /// Queues a stream response, returns `false` if the channel is closed.
+    ///
+    /// The response is dropped if the queue is full, leaving [`REPLY_SLOTS`] free for replies.
     fn send(&self, id: Option<Value>, result: BrpResult) -> bool {
-        let res = self.0.force_send(BrpResponse::new(id, result));
+        if self.sender.is_closed() {
+            return false;
+        }
+
+        let capacity = self.sender.capacity().unwrap_or(usize::MAX);
+        if self.sender.len() + REPLY_SLOTS >= capacity {
+            self.record_overflow();
+            return true;
+        }
 
-        match res {
-            Ok(Some(_)) => {
-                warn!("Channel queue is full, dropping response. Consider increasing the channel size.");
+        match self.sender.try_send(BrpResponse::new(id, result)) {
+            Ok(()) => {
+                self.sent.fetch_add(1, Ordering::Relaxed);
+                self.stats.record_message_sent(self.client_id);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.record_overflow();
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Queues a reply to a client's request, returns `false` if the channel is closed.
+    ///
+    /// Replies are never dropped, if the queue is full the oldest response is dropped instead.
+    fn reply(&self, id: Option<Value>, result: BrpResult) -> bool {
+        match self.sender.force_send(BrpResponse::new(id, result)) {
+            Ok(displaced) => {
+                if displaced.is_some() {
+                    self.record_overflow();
+                } else {
+                    self.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                self.stats.record_message_sent(self.client_id);
+                true
             }
-            _ => {}
+            Err(_) => false,
         }
+    }
 
-        return res.is_ok();
+    fn record_overflow(&self) {
+        self.stats.record_dropped_response(self.client_id);
+        if !self.overflowed.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Channel queue is full, dropping response and pausing the stream until it drains."
+            );
+        }
+    }
+
+    /// Returns `true` if responses were dropped and the queue has drained since.
+    /// The overflow is cleared when this returns `true`.
+    fn take_drained_overflow(&self) -> bool {
+        self.sender.is_empty() && self.overflowed.swap(false, Ordering::Relaxed)
+    }
+
+    fn is_overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
     }
 }
 
 #[derive(Default, Clone, Copy, Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct StreamClientId(usize);
 
-fn setup_channel(mut commands: Commands) {
-    let (sender, receiver) = channel::bounded(CHANNEL_SIZE);
-    commands.insert_resource(StreamSender(sender));
-    commands.insert_resource(StreamReceiver(receiver));
+impl StreamClientId {
+    /// Allocates a new id, unique across all transports.
+    pub fn next() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 fn process_remote_requests(world: &mut World) {
@@ -229,9 +538,29 @@ fn process_remote_requests(world: &mut World) {
     }
 
     while let Ok(stream_message) = world.resource_mut::<StreamReceiver>().try_recv() {
+        if let StreamMessageKind::Request(request, access, sender) = stream_message.kind {
+            let response = run_one_shot(world, stream_message.client_id, request, access);
+            let _ = sender.force_send(response);
+            continue;
+        }
+
         world.resource_scope(
             |world, methods: Mut<StreamMethods>| match stream_message.kind {
                 StreamMessageKind::Connect(req_id, message) => {
+                    if let Some(session) = &message.session {
+                        if resume_stream(
+                            world,
+                            stream_message.client_id,
+                            &message.method,
+                            session,
+                            message.access,
+                            req_id.clone(),
+                            message.sender.clone(),
+                        ) {
+                            return;
+                        }
+                    }
+
                     let Some(handler) = methods.0.get(&message.method) else {
                         let _ = message.sender.force_send(BrpResponse::new(
                             req_id,
@@ -248,35 +577,77 @@ fn process_remote_requests(world: &mut World) {
                         client_id: stream_message.client_id,
                         params: message.params,
                     };
-                    let sender = ActiveStreamSender(message.sender);
+                    world
+                        .resource_mut::<ClientAccesses>()
+                        .0
+                        .insert(stream_message.client_id, message.access);
+                    let stats = world.resource::<StreamStats>().clone();
+                    stats.add_client(stream_message.client_id);
+                    let sender =
+                        ActiveStreamSender::new(message.sender, stream_message.client_id, stats);
+
+                    let on_connect_rejected = handler.on_connect.is_some_and(|on_connect| {
+                        run_handler(world, on_connect, &input, &sender, req_id.as_ref())
+                    });
+                    let min_interval = world
+                        .resource_mut::<StreamMinIntervals>()
+                        .0
+                        .remove(&stream_message.client_id)
+                        .or(handler.min_interval);
+                    if on_connect_rejected {
+                        sender.stats.remove_client(stream_message.client_id);
+                        world
+                            .resource_mut::<ClientAccesses>()
+                            .0
+                            .remove(&stream_message.client_id);
+                        return;
+                    }
 
-                    if let Some(on_connect) = handler.on_connect {
-                        if run_handler(world, on_connect, &input, &sender, req_id.as_ref()) {
-                            return;
-                        }
+                    let session = world
+                        .resource::<SessionGracePeriod>()
+                        .0
+                        .and_then(|_| match new_session_token() {
+                            Ok(session) => Some(session),
+                            Err(err) => {
+                                error!("Failed to create a session token, the stream can't be resumed: {err}");
+                                None
+                            }
+                        });
+                    if let Some(session) = &session {
+                        sender.reply(req_id.clone(), Ok(session_result(session, false)));
                     }
 
                     world.resource_mut::<ActiveStreams>().insert(
                         stream_message.client_id,
                         ActiveStream {
                             request_id: req_id,
+                            method: message.method,
                             input,
                             sender,
                             on_update: handler.update,
                             on_disconnect: handler.on_disconnect,
                             on_data: handler.on_data,
+                            on_resync: handler.on_resync,
+                            min_interval,
+                            last_update: None,
+                            session,
+                            suspended: None,
                         },
                     );
                 }
-                StreamMessageKind::Disconnect => {
-                    let stream = world
-                        .resource_mut::<ActiveStreams>()
-                        .remove(&stream_message.client_id);
-
-                    if let Some(stream) = stream {
-                        if let Some(on_disconnect) = stream.on_disconnect {
-                            let _ = world.run_system_with_input(on_disconnect, &stream.input);
+                StreamMessageKind::Disconnect => disconnect(world, stream_message.client_id),
+                StreamMessageKind::ConnectionLost => {
+                    let grace_period = world.resource::<SessionGracePeriod>().0;
+                    let mut streams = world.resource_mut::<ActiveStreams>();
+                    match (grace_period, streams.get_mut(&stream_message.client_id)) {
+                        (Some(grace_period), Some(stream)) if stream.session.is_some() => {
+                            debug!(
+                                "Client {:?} connection lost, keeping its stream",
+                                stream.input.client_id
+                            );
+                            stream.suspend(grace_period);
                         }
+                        _ => disconnect(world, stream_message.client_id),
                     }
                 }
                 StreamMessageKind::Data(value) => {
@@ -290,7 +661,7 @@ fn process_remote_requests(world: &mut World) {
                         let request: BrpRequest = match serde_json::from_value(value) {
                             Ok(v) => v,
                             Err(err) => {
-                                stream.sender.send(
+                                stream.sender.reply(
                                     None,
                                     Err(BrpError {
                                         code: error_codes::INVALID_REQUEST,
@@ -306,9 +677,15 @@ fn process_remote_requests(world: &mut World) {
                             return;
                         };
 
+                        // A resumed stream keeps the id of the client that opened it
                         let request_id = request.id.clone();
-                        let result = world
-                            .run_system_with_input(on_data, (stream_message.client_id, request));
+                        let start = Instant::now();
+                        let result =
+                            world.run_system_with_input(on_data, (stream.input.client_id, request));
+                        stream
+                            .sender
+                            .stats
+                            .record_handler_run(stream_message.client_id, start.elapsed());
 
                         match result {
                             Ok(result) => {
@@ -320,10 +697,10 @@ fn process_remote_requests(world: &mut World) {
                                     return;
                                 }
 
-                                stream.sender.send(request_id, result);
+                                stream.sender.reply(request_id, result);
                             }
                             Err(error) => {
-                                stream.sender.send(
+                                stream.sender.reply(
                                     request_id,
                                     Err(BrpError {
                                         code: error_codes::INTERNAL_ERROR,
@@ -335,14 +712,68 @@ fn process_remote_requests(world: &mut World) {
                         }
                     })
                 }
+                StreamMessageKind::Request(..) => unreachable!(),
             },
         );
     }
 
-    world.resource_scope(|world, mut streams: Mut<ActiveStreams>| {
-        let to_remove = streams
-            .iter()
+    let now = Instant::now();
+    let expired = world
+        .resource::<ActiveStreams>()
+        .iter()
+        .filter(|(_, stream)| {
+            stream
+                .suspended
+                .as_ref()
+                .is_some_and(|suspended| suspended.until <= now)
+        })
+        .map(|(client_id, _)| *client_id)
+        .collect::<Vec<_>>();
+    for client_id in expired {
+        debug!("Client {client_id:?} did not reconnect, closing its stream");
+        disconnect(world, client_id);
+    }
+
+    let to_remove = world.resource_scope(|world, mut streams: Mut<ActiveStreams>| {
+        streams
+            .iter_mut()
             .filter_map(|(client_id, stream)| {
+                // Wait for the client to reconnect, or for the transport to report the lost connection
+                if stream.suspended.is_some()
+                    || (stream.session.is_some() && stream.sender.sender.is_closed())
+                {
+                    return None;
+                }
+
+                if stream.sender.take_drained_overflow() {
+                    debug!("Client {client_id:?} queue drained, resyncing");
+                    if let Some(on_resync) = stream.on_resync {
+                        let _ = world.run_system_with_input(on_resync, &stream.input);
+                    }
+                }
+
+                // Send the result of the previous update once it is ready
+                let mut tasks = world.resource_mut::<StreamTasks>();
+                if let Some(task) = tasks.0.get_mut(&stream.input.client_id) {
+                    let (result, serialization_time) = block_on(poll_once(task))?;
+
+                    tasks.0.remove(&stream.input.client_id);
+                    stream
+                        .sender
+                        .stats
+                        .record_serialization_time(*client_id, serialization_time);
+                    if let Some(result) = result {
+                        if send_result(&stream.sender, stream.request_id.as_ref(), result) {
+                            return Some(*client_id);
+                        }
+                    }
+                }
+
+                // Pause incremental updates until the client catches up
+                if stream.sender.is_overflowed() || !stream.is_update_due() {
+                    return None;
+                }
+
                 run_handler(
                     world,
                     stream.on_update,
@@ -352,16 +783,160 @@ fn process_remote_requests(world: &mut World) {
                 )
                 .then_some(*client_id)
             })
-            .collect::<Vec<_>>();
+            .collect::<Vec<_>>()
+    });
+
+    // The transport may only report the lost connection after the stream is removed
+    for client_id in to_remove {
+        disconnect(world, client_id);
+    }
+}
 
-        for client_id in to_remove {
-            streams.remove(&client_id);
+fn run_one_shot(
+    world: &mut World,
+    client_id: StreamClientId,
+    request: BrpRequest,
+    access: ClientAccess,
+) -> BrpResponse {
+    let Some(handler) = world
+        .resource::<OneShotMethods>()
+        .0
+        .get(&request.method)
+        .copied()
+    else {
+        return BrpResponse::new(
+            request.id,
+            Err(BrpError {
+                code: error_codes::METHOD_NOT_FOUND,
+                message: format!("Method `{}` not found", request.method),
+                data: None,
+            }),
+        );
+    };
+
+    let request_id = request.id.clone();
+    world
+        .resource_mut::<ClientAccesses>()
+        .0
+        .insert(client_id, access);
+    let result = match world.run_system_with_input(handler, (client_id, request)) {
+        Ok(result) => result.unwrap_or(Ok(Value::Null)),
+        Err(error) => Err(BrpError {
+            code: error_codes::INTERNAL_ERROR,
+            message: format!("Failed to run method handler: {error}"),
+            data: None,
+        }),
+    };
+    world.resource_mut::<ClientAccesses>().0.remove(&client_id);
+
+    BrpResponse::new(request_id, result)
+}
+
+fn disconnect(world: &mut World, client_id: StreamClientId) {
+    let stream = world.resource_mut::<ActiveStreams>().remove(&client_id);
+
+    if let Some(stream) = stream {
+        stream.sender.stats.remove_client(client_id);
+        world
+            .resource_mut::<StreamTasks>()
+            .0
+            .remove(&stream.input.client_id);
+        if let Some(on_disconnect) = stream.on_disconnect {
+            let _ = world.run_system_with_input(on_disconnect, &stream.input);
         }
-    });
+        world
+            .resource_mut::<ClientAccesses>()
+            .0
+            .remove(&stream.input.client_id);
+    }
 }
 
-fn on_app_exit(mut active_streams: ResMut<ActiveStreams>) {
+/// Moves the stream of the `session` to the new connection of `client_id`.
+/// Returns `false` if there is no such stream of `method` opened with the same `access`,
+/// e.g. its grace period is over.
+fn resume_stream(
+    world: &mut World,
+    client_id: StreamClientId,
+    method: &str,
+    session: &SessionResume,
+    access: ClientAccess,
+    request_id: Option<Value>,
+    sender: Sender<BrpResponse>,
+) -> bool {
+    let accesses = world.resource::<ClientAccesses>();
+    let Some(previous_id) = world
+        .resource::<ActiveStreams>()
+        .iter()
+        .find(|(_, stream)| {
+            stream.method == method
+                && stream.session.as_ref() == Some(&session.token)
+                && accesses.get(stream.input.client_id) == Some(access)
+        })
+        .map(|(client_id, _)| *client_id)
+    else {
+        return false;
+    };
+
+    let mut streams = world.resource_mut::<ActiveStreams>();
+    let mut stream = streams.remove(&previous_id).unwrap();
+    // The previous connection may not be known to be lost yet, responses sent to it since
+    // the client's last one are lost either way
+    stream.suspended = None;
+    let lost_responses = stream.sender.is_overflowed()
+        || session.last_seq != Some(stream.sender.sent.load(Ordering::Relaxed));
+
+    let stats = stream.sender.stats.clone();
+    stats.move_client(previous_id, client_id);
+    stream.request_id = request_id;
+    stream.sender = ActiveStreamSender::new(sender, client_id, stats);
+    stream.sender.reply(
+        stream.request_id.clone(),
+        Ok(session_result(&session.token, true)),
+    );
+
+    debug!(
+        "Client {:?} resumed its stream as {client_id:?}",
+        stream.input.client_id
+    );
+
+    let on_resync = stream.on_resync.filter(|_| lost_responses);
+    streams.insert(client_id, stream);
+
+    if let Some(on_resync) = on_resync {
+        world.resource_scope(|world, streams: Mut<ActiveStreams>| {
+            let _ = world.run_system_with_input(on_resync, &streams[&client_id].input);
+        });
+    }
+
+    true
+}
+
+/// Returns 128 random bits from the OS, hex encoded.
+/// Anyone with the token can take over the stream, it must not be guessable.
+fn new_session_token() -> Result<String, getrandom::Error> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)?;
+
+    Ok(bytes.iter().fold(String::new(), |mut token, byte| {
+        let _ = write!(token, "{byte:02x}");
+        token
+    }))
+}
+
+fn session_result(session: &str, resumed: bool) -> Value {
+    json!({ "session": session, "resumed": resumed })
+}
+
+fn on_app_exit(
+    mut active_streams: ResMut<ActiveStreams>,
+    mut tasks: ResMut<StreamTasks>,
+    mut accesses: ResMut<ClientAccesses>,
+    stats: Res<StreamStats>,
+) {
     active_streams.clear();
+    tasks.0.clear();
+    accesses.0.clear();
+    stats.clear();
 }
 
 #[must_use]
@@ -372,16 +947,16 @@ fn run_handler(
     sender: &ActiveStreamSender,
     request_id: Option<&Value>,
 ) -> bool {
+    let start = Instant::now();
     let result = world.run_system_with_input(system_id, &(input));
+    sender
+        .stats
+        .record_handler_run(sender.client_id, start.elapsed());
 
     match result {
         Ok(handler_result) => {
             if let Some(handler_result) = handler_result {
-                let handler_err = handler_result.is_err();
-                let channel_ok = sender.send(request_id.cloned(), handler_result);
-
-                // Remove when the handler return error or channel closed
-                handler_err || !channel_ok
+                send_result(sender, request_id, handler_result)
             } else {
                 false
             }
@@ -400,3 +975,13 @@ fn run_handler(
         }
     }
 }
+
+/// Sends the result of a handler, returns `true` if the stream should be removed.
+#[must_use]
+fn send_result(sender: &ActiveStreamSender, request_id: Option<&Value>, result: BrpResult) -> bool {
+    let handler_err = result.is_err();
+    let channel_ok = sender.send(request_id.cloned(), result);
+
+    // Remove when the handler return error or channel closed
+    handler_err || !channel_ok
+}
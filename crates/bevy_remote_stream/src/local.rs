@@ -0,0 +1,211 @@
+use anyhow::anyhow;
+use bevy::{
+    prelude::*,
+    remote::{BrpRequest, BrpResponse},
+    utils::Instant,
+};
+use serde_json::{json, Value};
+use smol::channel::{self, Receiver, Sender};
+
+use crate::{
+    stats::StreamStats, BrpStreamMessage, ClientAccess, SessionResume, StreamClientId,
+    StreamMessage, StreamMessageKind, StreamSender,
+};
+
+/// Size of the response queue of a [`LocalStreamClient`].
+const RESPONSE_CHANNEL_SIZE: usize = 32;
+
+/// A client connected to a stream from the same process, without any socket.
+///
+/// Messages are processed by [`RemoteStreamPlugin`](crate::RemoteStreamPlugin) on the next
+/// [`App::update`], which makes it suitable to drive stream handlers from tests or embedded tools.
+/// The client is disconnected when dropped.
+pub struct LocalStreamClient {
+    id: StreamClientId,
+    sender: Sender<StreamMessage>,
+    receiver: Receiver<BrpResponse>,
+    next_request_id: u64,
+    stats: StreamStats,
+    /// Whether the stream is disconnected when the client is dropped.
+    connected: bool,
+}
+
+impl LocalStreamClient {
+    /// Connects to the stream `method` of the [`RemoteStreamPlugin`](crate::RemoteStreamPlugin)
+    /// added to `world`. Stream updates are sent with the request id `0`.
+    pub fn connect(
+        world: &World,
+        method: impl Into<String>,
+        params: Option<Value>,
+    ) -> anyhow::Result<Self> {
+        Self::connect_with_access(world, method, params, ClientAccess::ReadWrite)
+    }
+
+    /// Connects like [`Self::connect`] with the given access instead of full access.
+    pub fn connect_with_access(
+        world: &World,
+        method: impl Into<String>,
+        params: Option<Value>,
+        access: ClientAccess,
+    ) -> anyhow::Result<Self> {
+        Self::open(world, method.into(), params, access, None)
+    }
+
+    /// Connects like [`Self::connect_with_access`], resuming the stream of `session` instead
+    /// if it is still kept, see [`SessionGracePeriod`](crate::SessionGracePeriod).
+    pub fn resume(
+        world: &World,
+        method: impl Into<String>,
+        params: Option<Value>,
+        access: ClientAccess,
+        session: SessionResume,
+    ) -> anyhow::Result<Self> {
+        Self::open(world, method.into(), params, access, Some(session))
+    }
+
+    fn open(
+        world: &World,
+        method: String,
+        params: Option<Value>,
+        access: ClientAccess,
+        session: Option<SessionResume>,
+    ) -> anyhow::Result<Self> {
+        let sender = world
+            .get_resource::<StreamSender>()
+            .ok_or_else(|| anyhow!("RemoteStreamPlugin is not added"))?
+            .0
+            .clone();
+        let (result_sender, receiver) = channel::bounded(RESPONSE_CHANNEL_SIZE);
+
+        let client = Self {
+            id: StreamClientId::next(),
+            sender,
+            receiver,
+            next_request_id: 1,
+            stats: world.resource::<StreamStats>().clone(),
+            connected: true,
+        };
+        client.send(StreamMessageKind::Connect(
+            Some(Value::from(0)),
+            BrpStreamMessage {
+                method,
+                params,
+                sender: result_sender,
+                session,
+                access,
+            },
+        ))?;
+
+        Ok(client)
+    }
+
+    pub fn id(&self) -> StreamClientId {
+        self.id
+    }
+
+    /// Sends a request to the stream `on_data` handler and returns its id.
+    pub fn request(&mut self, method: &str, params: Option<Value>) -> anyhow::Result<Value> {
+        let id = Value::from(self.next_request_id);
+        self.next_request_id += 1;
+
+        self.send_data(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }))?;
+
+        Ok(id)
+    }
+
+    /// Sends a raw message to the stream `on_data` handler.
+    pub fn send_data(&self, message: Value) -> anyhow::Result<()> {
+        self.send(StreamMessageKind::Data(message))
+    }
+
+    /// Returns the next response, if any.
+    ///
+    /// Responses are not encoded, their size as JSON is recorded in [`StreamStats`] instead.
+    pub fn try_recv(&self) -> Option<BrpResponse> {
+        let response = self.receiver.try_recv().ok()?;
+        let start = Instant::now();
+        let bytes = serde_json::to_vec(&response).map_or(0, |json| json.len());
+        self.stats
+            .record_bytes_sent(self.id, bytes, start.elapsed());
+
+        Some(response)
+    }
+
+    /// Returns all the responses received so far.
+    pub fn drain(&self) -> Vec<BrpResponse> {
+        std::iter::from_fn(|| self.try_recv()).collect()
+    }
+
+    /// Stops receiving responses without disconnecting,
+    /// like a connection that failed before its transport noticed it.
+    pub fn close_receiver(&self) {
+        self.receiver.close();
+    }
+
+    /// Drops the connection without disconnecting, like a transport losing it.
+    /// The stream is kept for the [`SessionGracePeriod`](crate::SessionGracePeriod) if it has
+    /// a session.
+    pub fn lose_connection(mut self) -> anyhow::Result<()> {
+        self.connected = false;
+        self.send(StreamMessageKind::ConnectionLost)
+    }
+
+    /// Returns `true` once the stream was closed by the server.
+    pub fn is_closed(&self) -> bool {
+        self.receiver.is_closed()
+    }
+
+    fn send(&self, kind: StreamMessageKind) -> anyhow::Result<()> {
+        self.sender
+            .try_send(StreamMessage {
+                client_id: self.id,
+                kind,
+            })
+            .map_err(|err| anyhow!("Failed to send stream message: {err}"))
+    }
+}
+
+/// Sends a request to the [`OneShotMethods`](crate::OneShotMethods) of `world` with the given
+/// access, like a plain HTTP request. The response is received on the next [`App::update`].
+pub fn request_one_shot(
+    world: &World,
+    method: impl Into<String>,
+    params: Option<Value>,
+    access: ClientAccess,
+) -> anyhow::Result<Receiver<BrpResponse>> {
+    let sender = world
+        .get_resource::<StreamSender>()
+        .ok_or_else(|| anyhow!("RemoteStreamPlugin is not added"))?;
+    let (response_sender, response_receiver) = channel::bounded(1);
+    let request = BrpRequest {
+        jsonrpc: "2.0".into(),
+        method: method.into(),
+        id: Some(Value::from(1)),
+        params,
+    };
+
+    sender
+        .0
+        .try_send(StreamMessage {
+            client_id: StreamClientId::next(),
+            kind: StreamMessageKind::Request(request, access, response_sender),
+        })
+        .map_err(|err| anyhow!("Failed to send request: {err}"))?;
+
+    Ok(response_receiver)
+}
+
+impl Drop for LocalStreamClient {
+    fn drop(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        let _ = self.send(StreamMessageKind::Disconnect);
+    }
+}
@@ -0,0 +1,460 @@
+use std::future::Future;
+
+use bevy::{
+    remote::{error_codes, BrpError, BrpRequest, BrpResponse, BrpResult},
+    tasks::IoTaskPool,
+    utils::HashMap,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use smol::channel::{self, Receiver, Sender};
+
+use crate::{BrpStreamMessage, ClientAccess, StreamClientId, StreamMessage, StreamMessageKind};
+
+/// Opens a stream, `params` being the stream request (`method` and `params`).
+/// The id of this request identifies the stream: updates are sent with it and
+/// requests to the stream must set it as their `stream` field.
+pub const OPEN_METHOD: &str = "stream/open";
+
+/// Closes the stream identified by the `id` param.
+pub const CLOSE_METHOD: &str = "stream/close";
+
+/// Notification sent when the server closed the stream identified by the `id` param,
+/// e.g. because its handler failed. The id can then be used to open a new stream.
+pub const CLOSED_NOTIFICATION: &str = "stream/closed";
+
+/// Size of the response queue of each stream.
+const STREAM_CHANNEL_SIZE: usize = 32;
+
+/// A message written to a connection.
+pub(crate) enum OutgoingMessage {
+    /// A response of the stream of the client.
+    Response(StreamClientId, BrpResponse),
+    /// A JSON-RPC notification.
+    Notification(Value),
+}
+
+/// Creates the response channel of the stream of `client_id`.
+///
+/// The returned future forwards the responses to `connection` and completes once
+/// the server removed the stream.
+pub(crate) fn forward_responses(
+    client_id: StreamClientId,
+    connection: Sender<OutgoingMessage>,
+) -> (Sender<BrpResponse>, impl Future<Output = ()>) {
+    let (sender, receiver) = channel::bounded(STREAM_CHANNEL_SIZE);
+    let forward = async move {
+        while let Ok(response) = receiver.recv().await {
+            let message = OutgoingMessage::Response(client_id, response);
+            if connection.send(message).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    (sender, forward)
+}
+
+#[derive(Deserialize)]
+struct OpenParams {
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct CloseParams {
+    id: Value,
+}
+
+/// Several streams sharing a single connection.
+///
+/// Each stream gets its own [`StreamClientId`], so stream handlers see them as separate clients.
+pub(crate) struct Multiplexer {
+    request_sender: Sender<StreamMessage>,
+    connection: Sender<OutgoingMessage>,
+    /// Client id of the connection, replies of the multiplexer itself are sent with it.
+    client_id: StreamClientId,
+    /// Access granted to the connection, shared by all of its streams.
+    access: ClientAccess,
+    /// Client ids of the open streams, keyed by the serialized stream id.
+    streams: HashMap<String, StreamClientId>,
+    /// Streams whose response channel was closed by the server.
+    closed_sender: Sender<(Value, StreamClientId)>,
+    closed_receiver: Receiver<(Value, StreamClientId)>,
+}
+
+impl Multiplexer {
+    pub fn new(
+        request_sender: Sender<StreamMessage>,
+        connection: Sender<OutgoingMessage>,
+        client_id: StreamClientId,
+        access: ClientAccess,
+    ) -> Self {
+        let (closed_sender, closed_receiver) = channel::unbounded();
+        Self {
+            request_sender,
+            connection,
+            client_id,
+            access,
+            streams: HashMap::default(),
+            closed_sender,
+            closed_receiver,
+        }
+    }
+
+    /// Waits for the server to close a stream, see [`Multiplexer::reap`].
+    pub async fn next_closed(&self) -> (Value, StreamClientId) {
+        // The multiplexer holds a sender, the channel is never closed
+        self.closed_receiver.recv().await.unwrap()
+    }
+
+    /// Forgets a stream closed by the server and notifies the client,
+    /// unless the client closed it first.
+    pub async fn reap(&mut self, id: Value, client_id: StreamClientId) -> anyhow::Result<()> {
+        if self.streams.get(&key(&id)) != Some(&client_id) {
+            return Ok(());
+        }
+
+        self.streams.remove(&key(&id));
+        self.connection
+            .send(OutgoingMessage::Notification(json!({
+                "jsonrpc": "2.0",
+                "method": CLOSED_NOTIFICATION,
+                "params": { "id": id },
+            })))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Handles a message received on the connection.
+    pub async fn handle(&mut self, message: Value) -> anyhow::Result<()> {
+        let stream_id = message.get("stream").cloned();
+        let request = match serde_json::from_value::<BrpRequest>(message) {
+            Ok(request) => request,
+            Err(err) => {
+                return self
+                    .reply(None, error(format!("Failed to parse request: {err}")))
+                    .await;
+            }
+        };
+
+        match request.method.as_str() {
+            OPEN_METHOD => self.open(request).await,
+            CLOSE_METHOD => self.close(request).await,
+            _ => {
+                let Some(client_id) = stream_id.and_then(|id| self.streams.get(&key(&id))) else {
+                    return self
+                        .reply(request.id, error("Missing or unknown `stream`".into()))
+                        .await;
+                };
+
+                self.send(
+                    *client_id,
+                    StreamMessageKind::Data(serde_json::to_value(request)?),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Disconnects all the open streams.
+    pub async fn close_all(&mut self) -> anyhow::Result<()> {
+        for (_, client_id) in self.streams.drain() {
+            self.request_sender
+                .send(StreamMessage {
+                    client_id,
+                    kind: StreamMessageKind::Disconnect,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn open(&mut self, request: BrpRequest) -> anyhow::Result<()> {
+        let Some(id) = request.id else {
+            return self
+                .reply(None, error("Opening a stream requires an id".into()))
+                .await;
+        };
+
+        let params = match request.params.map(serde_json::from_value::<OpenParams>) {
+            Some(Ok(params)) => params,
+            Some(Err(err)) => return self.reply(Some(id), error(err.to_string())).await,
+            None => return self.reply(Some(id), error("Missing params".into())).await,
+        };
+
+        if self.streams.contains_key(&key(&id)) {
+            return self
+                .reply(
+                    Some(id),
+                    error("A stream with this id is already open".into()),
+                )
+                .await;
+        }
+
+        let client_id = StreamClientId::next();
+        self.streams.insert(key(&id), client_id);
+
+        let (sender, forward) = forward_responses(client_id, self.connection.clone());
+        let (closed_sender, closed_id) = (self.closed_sender.clone(), id.clone());
+        IoTaskPool::get()
+            .spawn(async move {
+                forward.await;
+                let _ = closed_sender.send((closed_id, client_id)).await;
+            })
+            .detach();
+
+        self.send(
+            client_id,
+            StreamMessageKind::Connect(
+                Some(id),
+                BrpStreamMessage {
+                    method: params.method,
+                    params: params.params,
+                    sender,
+                    session: None,
+                    access: self.access,
+                },
+            ),
+        )
+        .await
+    }
+
+    async fn close(&mut self, request: BrpRequest) -> anyhow::Result<()> {
+        let params = match request.params.map(serde_json::from_value::<CloseParams>) {
+            Some(Ok(params)) => params,
+            Some(Err(err)) => return self.reply(request.id, error(err.to_string())).await,
+            None => return self.reply(request.id, error("Missing params".into())).await,
+        };
+
+        let Some(client_id) = self.streams.remove(&key(&params.id)) else {
+            return self.reply(request.id, error("Unknown stream".into())).await;
+        };
+
+        self.send(client_id, StreamMessageKind::Disconnect).await?;
+
+        if request.id.is_some() {
+            self.reply(request.id, Ok(Value::Null)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, client_id: StreamClientId, kind: StreamMessageKind) -> anyhow::Result<()> {
+        self.request_sender
+            .send(StreamMessage { client_id, kind })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn reply(&self, id: Option<Value>, result: BrpResult) -> anyhow::Result<()> {
+        self.connection
+            .send(OutgoingMessage::Response(
+                self.client_id,
+                BrpResponse::new(id, result),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn key(id: &Value) -> String {
+    id.to_string()
+}
+
+fn error(message: String) -> BrpResult {
+    Err(BrpError {
+        code: error_codes::INVALID_REQUEST,
+        message,
+        data: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        remote::BrpPayload,
+        tasks::{block_on, TaskPool},
+    };
+
+    use super::*;
+
+    fn multiplexer() -> (
+        Multiplexer,
+        Receiver<StreamMessage>,
+        Receiver<OutgoingMessage>,
+    ) {
+        IoTaskPool::get_or_init(TaskPool::new);
+        let (request_sender, requests) = channel::unbounded();
+        let (connection, outgoing) = channel::unbounded();
+        let multiplexer = Multiplexer::new(
+            request_sender,
+            connection,
+            StreamClientId::next(),
+            ClientAccess::ReadOnly,
+        );
+        (multiplexer, requests, outgoing)
+    }
+
+    fn handle(multiplexer: &mut Multiplexer, message: Value) {
+        block_on(multiplexer.handle(message)).unwrap();
+    }
+
+    fn open(multiplexer: &mut Multiplexer, id: Value) {
+        handle(
+            multiplexer,
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": OPEN_METHOD,
+                "params": { "method": "test/stream", "params": { "id": id } },
+            }),
+        );
+    }
+
+    /// Returns the client id and the request of the stream opened by the next message.
+    fn connected(requests: &Receiver<StreamMessage>) -> (StreamClientId, BrpStreamMessage) {
+        let message = requests.try_recv().unwrap();
+        let StreamMessageKind::Connect(_, stream) = message.kind else {
+            panic!("Expected a stream to be opened");
+        };
+        (message.client_id, stream)
+    }
+
+    fn reply(outgoing: &Receiver<OutgoingMessage>) -> BrpResponse {
+        match outgoing.try_recv().unwrap() {
+            OutgoingMessage::Response(_, response) => response,
+            OutgoingMessage::Notification(notification) => {
+                panic!("Unexpected notification {notification}")
+            }
+        }
+    }
+
+    fn error_message(response: BrpResponse) -> String {
+        match response.payload {
+            BrpPayload::Error(err) => err.message,
+            BrpPayload::Result(result) => panic!("Unexpected result {result}"),
+        }
+    }
+
+    #[test]
+    fn opened_streams_receive_their_requests() {
+        let (mut multiplexer, requests, outgoing) = multiplexer();
+        open(&mut multiplexer, json!("a"));
+        open(&mut multiplexer, json!("b"));
+
+        let (a, stream) = connected(&requests);
+        assert_eq!(stream.method, "test/stream");
+        assert_eq!(stream.params, Some(json!({ "id": "a" })));
+        assert_eq!(stream.access, ClientAccess::ReadOnly);
+        let (b, _) = connected(&requests);
+        assert_ne!(a, b);
+        assert_ne!(a, multiplexer.client_id);
+
+        handle(
+            &mut multiplexer,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "test/request", "stream": "b" }),
+        );
+        let message = requests.try_recv().unwrap();
+        assert_eq!(message.client_id, b);
+        let StreamMessageKind::Data(data) = message.kind else {
+            panic!("Expected the request to be forwarded");
+        };
+        assert_eq!(data["method"], "test/request");
+        assert!(outgoing.is_empty());
+    }
+
+    #[test]
+    fn closed_streams_are_disconnected() {
+        let (mut multiplexer, requests, outgoing) = multiplexer();
+        open(&mut multiplexer, json!("a"));
+        let (a, _) = connected(&requests);
+
+        let close = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": CLOSE_METHOD,
+            "params": { "id": "a" },
+        });
+        handle(&mut multiplexer, close.clone());
+        let message = requests.try_recv().unwrap();
+        assert_eq!(message.client_id, a);
+        assert!(matches!(message.kind, StreamMessageKind::Disconnect));
+        let response = reply(&outgoing);
+        assert_eq!(response.id, Some(json!(1)));
+        assert!(matches!(response.payload, BrpPayload::Result(Value::Null)));
+
+        handle(&mut multiplexer, close);
+        assert_eq!(error_message(reply(&outgoing)), "Unknown stream");
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn stream_ids_are_unique() {
+        let (mut multiplexer, requests, outgoing) = multiplexer();
+        open(&mut multiplexer, json!("a"));
+        connected(&requests);
+
+        open(&mut multiplexer, json!("a"));
+        let response = reply(&outgoing);
+        assert_eq!(response.id, Some(json!("a")));
+        assert_eq!(
+            error_message(response),
+            "A stream with this id is already open"
+        );
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn requests_to_unknown_streams_are_rejected() {
+        let (mut multiplexer, requests, outgoing) = multiplexer();
+        open(&mut multiplexer, json!("a"));
+        connected(&requests);
+
+        handle(
+            &mut multiplexer,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "test/request", "stream": "b" }),
+        );
+        handle(
+            &mut multiplexer,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "test/request" }),
+        );
+
+        for id in [1, 2] {
+            let response = reply(&outgoing);
+            assert_eq!(response.id, Some(json!(id)));
+            assert_eq!(error_message(response), "Missing or unknown `stream`");
+        }
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn reaped_streams_are_notified() {
+        let (mut multiplexer, requests, outgoing) = multiplexer();
+        open(&mut multiplexer, json!("a"));
+        let (a, _) = connected(&requests);
+
+        block_on(multiplexer.reap(json!("a"), a)).unwrap();
+        let Ok(OutgoingMessage::Notification(notification)) = outgoing.try_recv() else {
+            panic!("Expected a notification");
+        };
+        assert_eq!(notification["method"], CLOSED_NOTIFICATION);
+        assert_eq!(notification["params"]["id"], "a");
+
+        // The id can be reused once the stream is closed
+        open(&mut multiplexer, json!("a"));
+        let (reopened, _) = connected(&requests);
+        assert!(outgoing.is_empty());
+
+        // The previous stream closing late doesn't affect the new one
+        block_on(multiplexer.reap(json!("a"), a)).unwrap();
+        assert!(outgoing.is_empty());
+        block_on(multiplexer.close_all()).unwrap();
+        assert_eq!(requests.try_recv().unwrap().client_id, reopened);
+    }
+}
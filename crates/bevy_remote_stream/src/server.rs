@@ -0,0 +1,65 @@
+use bevy::{prelude::*, remote::BrpRequest};
+use smol::channel::{self, Receiver, Sender};
+
+/// Status of a server listening on an address of type `A`, updated from its events.
+#[derive(Debug, Resource, Clone)]
+pub struct ServerStatus<A: Send + Sync + 'static> {
+    /// The address the server is listening on, `None` if it is not running.
+    pub listening: Option<A>,
+    /// Number of connected clients.
+    pub clients: usize,
+    /// The last error that prevented the server from running.
+    pub last_error: Option<String>,
+}
+
+impl<A: Send + Sync + 'static> Default for ServerStatus<A> {
+    fn default() -> Self {
+        Self {
+            listening: None,
+            clients: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// An event sent by the tasks of a server, applied to its [`ServerStatus`].
+pub(crate) trait ServerEvent: Event + Clone {
+    type Address: Send + Sync + 'static;
+
+    fn update(&self, status: &mut ServerStatus<Self::Address>);
+}
+
+/// Channel of the events sent by the server tasks.
+#[derive(Resource)]
+pub(crate) struct ServerEvents<E: ServerEvent> {
+    pub(crate) sender: Sender<E>,
+    receiver: Receiver<E>,
+}
+
+impl<E: ServerEvent> Default for ServerEvents<E> {
+    fn default() -> Self {
+        let (sender, receiver) = channel::unbounded();
+        Self { sender, receiver }
+    }
+}
+
+/// Forwards the events of the server tasks to the app and updates the server status.
+pub(crate) fn process_server_events<E: ServerEvent>(
+    events: Res<ServerEvents<E>>,
+    mut status: ResMut<ServerStatus<E::Address>>,
+    mut writer: EventWriter<E>,
+) {
+    while let Ok(event) = events.receiver.try_recv() {
+        event.update(&mut status);
+        writer.send(event);
+    }
+}
+
+/// Checks a JSON-RPC request received by a server.
+pub(crate) fn validate_request(request: BrpRequest) -> anyhow::Result<BrpRequest> {
+    if request.jsonrpc != "2.0" {
+        anyhow::bail!("JSON-RPC request requires `\"jsonrpc\": \"2.0\"`")
+    }
+
+    Ok(request)
+}
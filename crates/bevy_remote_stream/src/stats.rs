@@ -0,0 +1,177 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    diagnostic::{DiagnosticPath, Diagnostics},
+    prelude::*,
+    utils::{Duration, HashMap},
+};
+
+use crate::StreamClientId;
+
+/// Number of clients with an active stream.
+pub const CLIENTS: DiagnosticPath = DiagnosticPath::const_new("remote_stream/clients");
+/// Responses sent to all the clients during the frame.
+pub const MESSAGES_SENT: DiagnosticPath = DiagnosticPath::const_new("remote_stream/messages_sent");
+/// Bytes written to all the clients during the frame.
+pub const BYTES_SENT: DiagnosticPath = DiagnosticPath::const_new("remote_stream/bytes_sent");
+/// Responses dropped during the frame because a client's queue was full.
+pub const DROPPED_RESPONSES: DiagnosticPath =
+    DiagnosticPath::const_new("remote_stream/dropped_responses");
+/// Time spent running the stream handlers during the frame, in milliseconds.
+pub const HANDLER_TIME: DiagnosticPath = DiagnosticPath::const_new("remote_stream/handler_time");
+/// Time spent encoding the responses during the frame, in milliseconds.
+pub const SERIALIZATION_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("remote_stream/serialization_time");
+
+/// Statistics of a stream client, or of all the clients for [`StreamStats::total`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClientStats {
+    /// Responses queued to the client.
+    pub messages_sent: u64,
+    /// Bytes written to the client by the transport.
+    pub bytes_sent: u64,
+    /// Responses dropped because the client's queue was full.
+    pub dropped_responses: u64,
+    /// Number of handler runs for the client.
+    pub handler_runs: u64,
+    /// Time spent running the handlers for the client.
+    pub handler_time: Duration,
+    /// Time spent serializing the responses, by [`StreamTasks`](crate::StreamTasks)
+    /// and by the transport encoding them.
+    pub serialization_time: Duration,
+}
+
+impl ClientStats {
+    fn delta(&self, previous: &ClientStats) -> ClientStats {
+        ClientStats {
+            messages_sent: self.messages_sent - previous.messages_sent,
+            bytes_sent: self.bytes_sent - previous.bytes_sent,
+            dropped_responses: self.dropped_responses - previous.dropped_responses,
+            handler_runs: self.handler_runs - previous.handler_runs,
+            handler_time: self.handler_time - previous.handler_time,
+            serialization_time: self.serialization_time - previous.serialization_time,
+        }
+    }
+}
+
+/// Statistics of the connected stream clients.
+///
+/// It is shared with the transport tasks, which report what they write with
+/// [`StreamStats::record_bytes_sent`].
+#[derive(Resource, Clone, Default)]
+pub struct StreamStats(Arc<Mutex<StatsInner>>);
+
+#[derive(Default)]
+struct StatsInner {
+    clients: HashMap<StreamClientId, ClientStats>,
+    /// Statistics of all the clients since the app started, including disconnected ones.
+    total: ClientStats,
+}
+
+impl StreamStats {
+    /// Returns the statistics of a connected client.
+    pub fn client(&self, client_id: StreamClientId) -> Option<ClientStats> {
+        self.0.lock().unwrap().clients.get(&client_id).copied()
+    }
+
+    /// Returns the statistics of all the connected clients.
+    pub fn clients(&self) -> Vec<(StreamClientId, ClientStats)> {
+        let inner = self.0.lock().unwrap();
+        inner
+            .clients
+            .iter()
+            .map(|(client_id, stats)| (*client_id, *stats))
+            .collect()
+    }
+
+    /// Returns the statistics of all the clients since the app started.
+    pub fn total(&self) -> ClientStats {
+        self.0.lock().unwrap().total
+    }
+
+    /// Records `bytes` written to the client, which took `serialization_time` to encode.
+    pub fn record_bytes_sent(
+        &self,
+        client_id: StreamClientId,
+        bytes: usize,
+        serialization_time: Duration,
+    ) {
+        self.update(client_id, |stats| {
+            stats.bytes_sent += bytes as u64;
+            stats.serialization_time += serialization_time;
+        });
+    }
+
+    pub(crate) fn record_serialization_time(&self, client_id: StreamClientId, time: Duration) {
+        self.update(client_id, |stats| stats.serialization_time += time);
+    }
+
+    pub(crate) fn record_message_sent(&self, client_id: StreamClientId) {
+        self.update(client_id, |stats| stats.messages_sent += 1);
+    }
+
+    pub(crate) fn record_dropped_response(&self, client_id: StreamClientId) {
+        self.update(client_id, |stats| stats.dropped_responses += 1);
+    }
+
+    pub(crate) fn record_handler_run(&self, client_id: StreamClientId, time: Duration) {
+        self.update(client_id, |stats| {
+            stats.handler_runs += 1;
+            stats.handler_time += time;
+        });
+    }
+
+    pub(crate) fn add_client(&self, client_id: StreamClientId) {
+        self.0
+            .lock()
+            .unwrap()
+            .clients
+            .insert(client_id, ClientStats::default());
+    }
+
+    pub(crate) fn remove_client(&self, client_id: StreamClientId) {
+        self.0.lock().unwrap().clients.remove(&client_id);
+    }
+
+    /// Keeps the statistics of a resumed stream under the id of its new connection.
+    pub(crate) fn move_client(&self, from: StreamClientId, to: StreamClientId) {
+        let mut inner = self.0.lock().unwrap();
+        let stats = inner.clients.remove(&from).unwrap_or_default();
+        inner.clients.insert(to, stats);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clients.clear();
+    }
+
+    fn update(&self, client_id: StreamClientId, f: impl Fn(&mut ClientStats)) {
+        let mut inner = self.0.lock().unwrap();
+        f(&mut inner.total);
+        // Clients without a stream, e.g. multiplexed connections, only count in the total
+        if let Some(stats) = inner.clients.get_mut(&client_id) {
+            f(stats);
+        }
+    }
+}
+
+pub(crate) fn measure_diagnostics(
+    stats: Res<StreamStats>,
+    mut diagnostics: Diagnostics,
+    mut previous: Local<ClientStats>,
+) {
+    let (clients, total) = {
+        let inner = stats.0.lock().unwrap();
+        (inner.clients.len(), inner.total)
+    };
+    let frame = total.delta(&previous);
+    *previous = total;
+
+    diagnostics.add_measurement(&CLIENTS, || clients as f64);
+    diagnostics.add_measurement(&MESSAGES_SENT, || frame.messages_sent as f64);
+    diagnostics.add_measurement(&BYTES_SENT, || frame.bytes_sent as f64);
+    diagnostics.add_measurement(&DROPPED_RESPONSES, || frame.dropped_responses as f64);
+    diagnostics.add_measurement(&HANDLER_TIME, || frame.handler_time.as_secs_f64() * 1000.);
+    diagnostics.add_measurement(&SERIALIZATION_TIME, || {
+        frame.serialization_time.as_secs_f64() * 1000.
+    });
+}
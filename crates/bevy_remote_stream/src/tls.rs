@@ -0,0 +1,40 @@
+use std::{fs, io::BufReader, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use futures_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+/// PEM encoded certificate chain and private key used to serve connections over TLS.
+#[derive(Debug, Clone)]
+pub enum TlsConfig {
+    /// Read the certificate chain and private key from PEM files when the server starts.
+    Files { cert: PathBuf, key: PathBuf },
+    /// In-memory PEM encoded certificate chain and private key.
+    Pem { cert: Vec<u8>, key: Vec<u8> },
+}
+
+impl TlsConfig {
+    pub(crate) fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let (cert, key) = match self {
+            TlsConfig::Files { cert, key } => (
+                fs::read(cert).with_context(|| format!("Failed to read {}", cert.display()))?,
+                fs::read(key).with_context(|| format!("Failed to read {}", key.display()))?,
+            ),
+            TlsConfig::Pem { cert, key } => (cert.clone(), key.clone()),
+        };
+
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert.as_slice()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid certificate")?;
+        anyhow::ensure!(!certs.is_empty(), "No certificate found");
+
+        let key = rustls_pemfile::private_key(&mut BufReader::new(key.as_slice()))
+            .context("Invalid private key")?
+            .ok_or_else(|| anyhow::anyhow!("No private key found"))?;
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    io,
+    net::Shutdown,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::{
+    prelude::*,
+    remote::{error_codes, BrpError, BrpResponse},
+    tasks::IoTaskPool,
+    utils::Instant,
+};
+use serde_json::Value;
+use smol::{
+    channel::{self, Receiver, Sender},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    stream::StreamExt,
+    Async,
+};
+
+use crate::{
+    server::{process_server_events, validate_request, ServerEvent, ServerEvents, ServerStatus},
+    stats::StreamStats,
+    BrpStreamMessage, ClientAccess, StreamClientId, StreamMessage, StreamMessageKind, StreamSender,
+};
+
+/// The default path of the socket file, unique to this process so that several apps
+/// can run side by side.
+pub fn default_socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!("bevy_remote_stream-{}.sock", std::process::id()))
+}
+
+/// Add this plugin to your [`App`] to allow local connections over a Unix domain socket.
+///
+/// Messages are newline-delimited JSON-RPC: the first line is the stream request,
+/// the following lines are sent to the stream as data and every response is written on its own line.
+///
+/// The default socket path is [`default_socket_path`].
+/// The server reports its status in [`UnixSocketServerStatus`].
+pub struct RemoteStreamUnixSocketPlugin {
+    /// The path of the socket file.
+    path: PathBuf,
+}
+
+impl RemoteStreamUnixSocketPlugin {
+    /// Set the path of the socket file.
+    ///
+    /// A socket file left over by a previous run is removed, the server doesn't start if
+    /// another server is listening on it or if the file is not a socket.
+    #[must_use]
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+}
+
+impl Default for RemoteStreamUnixSocketPlugin {
+    fn default() -> Self {
+        Self {
+            path: default_socket_path(),
+        }
+    }
+}
+
+impl Plugin for RemoteStreamUnixSocketPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SocketPath(self.path.clone()))
+            .init_resource::<UnixSocketServerStatus>()
+            .init_resource::<ServerEvents<UnixSocketServerEvent>>()
+            .add_event::<UnixSocketServerEvent>()
+            .add_systems(Startup, start_server)
+            .add_systems(Update, process_server_events::<UnixSocketServerEvent>)
+            .add_systems(Last, remove_socket_file.run_if(on_event::<AppExit>));
+    }
+}
+
+#[derive(Debug, Resource)]
+pub struct SocketPath(pub PathBuf);
+
+/// Status of the Unix socket server, updated from [`UnixSocketServerEvent`]s.
+pub type UnixSocketServerStatus = ServerStatus<PathBuf>;
+
+/// Sent when the Unix socket server status changes, see [`UnixSocketServerStatus`].
+#[derive(Debug, Event, Clone)]
+pub enum UnixSocketServerEvent {
+    /// The server is listening on this path.
+    Listening(PathBuf),
+    /// The server stopped on an error, e.g. another server is listening on the path.
+    Failed(String),
+    /// A client connected.
+    ClientConnected(StreamClientId),
+    /// A client disconnected.
+    ClientDisconnected(StreamClientId),
+}
+
+impl ServerEvent for UnixSocketServerEvent {
+    type Address = PathBuf;
+
+    fn update(&self, status: &mut UnixSocketServerStatus) {
+        match self {
+            UnixSocketServerEvent::Listening(path) => {
+                info!("Stream server listening on {}", path.display());
+                status.listening = Some(path.clone());
+                status.last_error = None;
+            }
+            UnixSocketServerEvent::Failed(error) => {
+                error!("{error}");
+                status.listening = None;
+                status.last_error = Some(error.clone());
+            }
+            UnixSocketServerEvent::ClientConnected(_) => status.clients += 1,
+            UnixSocketServerEvent::ClientDisconnected(_) => {
+                status.clients = status.clients.saturating_sub(1);
+            }
+        }
+    }
+}
+
+fn start_server(
+    sender: Res<StreamSender>,
+    path: Res<SocketPath>,
+    events: Res<ServerEvents<UnixSocketServerEvent>>,
+    stats: Res<StreamStats>,
+) {
+    let (path, sender, events) = (path.0.clone(), sender.clone(), events.sender.clone());
+    let stats = stats.clone();
+    IoTaskPool::get()
+        .spawn(async move {
+            if let Err(err) = server_main(path.clone(), sender, events.clone(), stats).await {
+                let _ = events.try_send(UnixSocketServerEvent::Failed(format!(
+                    "Unix socket server on {} stopped: {err}",
+                    path.display()
+                )));
+            }
+        })
+        .detach();
+}
+
+/// Removes the socket file created by the server, so per-process paths don't pile up.
+fn remove_socket_file(status: Res<UnixSocketServerStatus>) {
+    if let Some(path) = &status.listening {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+async fn server_main(
+    path: PathBuf,
+    request_sender: Sender<StreamMessage>,
+    events: Sender<UnixSocketServerEvent>,
+    stats: StreamStats,
+) -> anyhow::Result<()> {
+    remove_stale_socket(&path)?;
+
+    let listener = Async::<UnixListener>::bind(&path)?;
+    let _ = events.try_send(UnixSocketServerEvent::Listening(path));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let request_sender = request_sender.clone();
+        let events = events.clone();
+        let stats = stats.clone();
+        IoTaskPool::get()
+            .spawn(async move {
+                let client_id = StreamClientId::next();
+                let _ = events.try_send(UnixSocketServerEvent::ClientConnected(client_id));
+                let _ = handle_client(client_id, Arc::new(stream), request_sender, stats).await;
+                let _ = events.try_send(UnixSocketServerEvent::ClientDisconnected(client_id));
+            })
+            .detach();
+    }
+}
+
+/// Removes the socket file left over by a previous run.
+/// Fails if a server is still listening on it or if the file is not a socket.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("the file exists and is not a socket");
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => anyhow::bail!("another server is listening on the socket"),
+        // Nothing accepts connections on the socket anymore
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn handle_client(
+    client_id: StreamClientId,
+    stream: Arc<Async<UnixStream>>,
+    request_sender: Sender<StreamMessage>,
+    stats: StreamStats,
+) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(&*stream).lines();
+
+    let Some(line) = lines.next().await else {
+        return Ok(());
+    };
+
+    let request = match serde_json::from_str(&line?)
+        .map_err(anyhow::Error::from)
+        .and_then(validate_request)
+    {
+        Ok(request) => request,
+        Err(err) => {
+            let response = BrpResponse::new(
+                None,
+                Err(BrpError {
+                    code: error_codes::INVALID_REQUEST,
+                    message: format!("{err}"),
+                    data: None,
+                }),
+            );
+            return write_response(&stream, &response, client_id, &stats).await;
+        }
+    };
+
+    let (result_sender, result_receiver) = channel::bounded(32);
+
+    IoTaskPool::get()
+        .spawn(send_stream_response(
+            stream.clone(),
+            result_receiver,
+            client_id,
+            stats,
+        ))
+        .detach();
+
+    request_sender
+        .send(StreamMessage {
+            client_id,
+            kind: StreamMessageKind::Connect(
+                request.id,
+                BrpStreamMessage {
+                    method: request.method,
+                    params: request.params,
+                    sender: result_sender,
+                    session: None,
+                    access: ClientAccess::ReadWrite,
+                },
+            ),
+        })
+        .await?;
+
+    while let Some(Ok(line)) = lines.next().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Invalid JSON is forwarded as a string so the stream replies with a parse error
+        let msg = serde_json::from_str::<Value>(&line).unwrap_or(Value::String(line));
+        request_sender
+            .send(StreamMessage {
+                client_id,
+                kind: StreamMessageKind::Data(msg),
+            })
+            .await?;
+    }
+
+    request_sender
+        .send(StreamMessage {
+            client_id,
+            kind: StreamMessageKind::Disconnect,
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn write_response(
+    stream: &Async<UnixStream>,
+    response: &BrpResponse,
+    client_id: StreamClientId,
+    stats: &StreamStats,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    stats.record_bytes_sent(client_id, line.len(), start.elapsed());
+
+    let mut writer = stream;
+    writer.write_all(&line).await?;
+
+    Ok(())
+}
+
+async fn send_stream_response(
+    stream: Arc<Async<UnixStream>>,
+    result_receiver: Receiver<BrpResponse>,
+    client_id: StreamClientId,
+    stats: StreamStats,
+) -> anyhow::Result<()> {
+    while let Ok(response) = result_receiver.recv().await {
+        write_response(&stream, &response, client_id, &stats).await?;
+    }
+
+    // The stream was closed by the server, stop reading from the client
+    let _ = stream.get_ref().shutdown(Shutdown::Both);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use bevy::ecs::system::InRef;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{RemoteStreamHandlersBuilder, RemoteStreamPlugin, StreamHandlerInput};
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "bevy_remote_stream-{name}-{}.sock",
+            std::process::id()
+        ))
+    }
+
+    /// Runs the app until `f` returns a value.
+    fn update_until<T>(app: &mut App, mut f: impl FnMut(&mut App) -> Option<T>) -> T {
+        let start = Instant::now();
+        loop {
+            app.update();
+            if let Some(value) = f(app) {
+                return value;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn streams_responses_over_the_socket() {
+        let path = socket_path("stream");
+        let mut app = App::new();
+        app.add_plugins((
+            TaskPoolPlugin::default(),
+            RemoteStreamPlugin::default().with_method(
+                "inspector/stream",
+                RemoteStreamHandlersBuilder::new(|InRef(input): InRef<StreamHandlerInput>| {
+                    Some(Ok(input.params.clone().unwrap_or_default()))
+                }),
+            ),
+            RemoteStreamUnixSocketPlugin::default().with_path(&path),
+        ));
+        update_until(&mut app, |app| {
+            app.world()
+                .resource::<UnixSocketServerStatus>()
+                .listening
+                .clone()
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        let client_path = path.clone();
+        thread::spawn(move || {
+            let mut stream = UnixStream::connect(client_path).unwrap();
+            writeln!(
+                stream,
+                r#"{{"jsonrpc":"2.0","id":1,"method":"inspector/stream","params":{{"hello":"world"}}}}"#
+            )
+            .unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            let _ = sender.send(line);
+        });
+
+        let line = update_until(&mut app, |_| receiver.try_recv().ok());
+        let response = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], json!({ "hello": "world" }));
+
+        app.world_mut().send_event(AppExit::Success);
+        app.update();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn live_sockets_are_not_removed() {
+        let path = socket_path("live");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,52 +1,113 @@
-use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::{
+    borrow::Cow,
+    future::Future,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+};
 
 use bevy::{
     prelude::*,
     remote::{error_codes, BrpError, BrpRequest, BrpResponse},
     tasks::IoTaskPool,
+    utils::{Duration, Instant},
 };
+use flate2::{write::DeflateEncoder, Compression};
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use http_body_util::Full;
+use http_body_util::{BodyExt, Full, Limited};
 use hyper::{
     body::{Bytes, Incoming},
     header::{
-        HeaderValue, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-        ACCESS_CONTROL_MAX_AGE, ORIGIN,
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, AUTHORIZATION, CONTENT_TYPE, ORIGIN,
+        SEC_WEBSOCKET_PROTOCOL, VARY,
     },
     server::conn::http1,
-    service, Method, Request, Response,
+    service, HeaderMap, Method, Request, Response, StatusCode, Uri,
 };
 use hyper_tungstenite::{HyperWebsocket, HyperWebsocketStream};
+use serde::Serialize;
 use serde_json::Value;
 use smol::{
     channel::{self, Receiver, Sender},
-    Async,
+    io::{AsyncRead, AsyncWrite},
+    Async, Timer,
 };
 use smol_hyper::rt::{FuturesIo, SmolTimer};
-use tungstenite::Message;
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
 
-use crate::{BrpStreamMessage, StreamClientId, StreamMessage, StreamMessageKind, StreamSender};
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
+use crate::{
+    mux::{self, Multiplexer, OutgoingMessage},
+    server::{process_server_events, validate_request, ServerEvent, ServerEvents, ServerStatus},
+    stats::StreamStats,
+    BrpStreamMessage, ClientAccess, SessionGracePeriod, SessionResume, StreamClientId,
+    StreamMessage, StreamMessageKind, StreamSender,
+};
 
 /// The default port that the WebSocket server will listen on.
 pub const DEFAULT_PORT: u16 = 3000;
 
 /// The default host address that WebSocket server will use.
 pub const DEFAULT_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+/// The default heartbeat of the connections.
+pub const DEFAULT_HEARTBEAT: Heartbeat = Heartbeat {
+    interval: Duration::from_secs(10),
+    timeout: Duration::from_secs(30),
+};
 /// Add this plugin to your [`App`] to allow remote connections to inspect and modify entities.
 ///
 /// The defaults are:
 /// - [`DEFAULT_ADDR`] : 127.0.0.1.
 /// - [`DEFAULT_PORT`] : 3000.
+/// - Message deflate disabled.
+/// - No token required.
+/// - Every origin allowed.
+/// - TLS disabled.
+/// - [`DEFAULT_HEARTBEAT`] : ping every 10 seconds, 30 seconds timeout.
+/// - Server enabled.
 ///
+/// The server is restarted when the `Host*` resources change, and can be stopped
+/// and started at runtime with the [`ServerEnabled`] resource.
 pub struct RemoteStreamWebSocketPlugin {
     /// The address that the WebSocket server will use.
     address: IpAddr,
 
     /// The port that the WebSocket server will listen on.
     port: u16,
+
+    /// The level of the application-level deflate offered to clients.
+    message_deflate: Option<u32>,
+
+    /// The token clients must provide to connect.
+    token: Option<String>,
+
+    /// The token clients can provide instead to connect with [`ClientAccess::ReadOnly`].
+    read_only_token: Option<String>,
+
+    /// The origins browsers are allowed to connect from, empty allows every origin.
+    allowed_origins: Vec<String>,
+
+    /// The certificate and key used to serve connections over TLS.
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+
+    /// Whether the server starts with the app.
+    enabled: bool,
+
+    /// Whether to listen on a port chosen by the OS when the port is in use.
+    port_fallback: bool,
+
+    /// How dead connections are detected.
+    heartbeat: Option<Heartbeat>,
 }
 
 impl RemoteStreamWebSocketPlugin {
@@ -63,6 +124,90 @@ impl RemoteStreamWebSocketPlugin {
         self.port = port;
         self
     }
+
+    /// Allow clients to opt in to deflated messages using the given `level` (0-9).
+    ///
+    /// This is a custom application-level scheme, not the `permessage-deflate` WebSocket
+    /// extension (RFC 7692) which the underlying WebSocket implementation doesn't support.
+    /// Clients opt in with the `message_deflate=raw` query parameter, every message sent to them
+    /// is then a binary frame holding the raw deflate (RFC 1951) stream of the encoded message,
+    /// which they must inflate themselves. Messages sent by clients are not deflated.
+    /// When this is not set, the query parameter is ignored and messages are sent as is.
+    #[must_use]
+    pub fn with_message_deflate(mut self, level: u32) -> Self {
+        self.message_deflate = Some(level.min(9));
+        self
+    }
+
+    /// Require clients to provide `token` to connect.
+    ///
+    /// The token is read from the `Authorization: Bearer <token>` header or,
+    /// as browsers can't set headers on WebSocket connections, the `token` query parameter.
+    #[must_use]
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Let clients providing `token` connect with [`ClientAccess::ReadOnly`],
+    /// clients providing the token of [`Self::with_token`] get full access.
+    ///
+    /// A token is then required to connect, even if [`Self::with_token`] is not set.
+    #[must_use]
+    pub fn with_read_only_token(mut self, token: impl Into<String>) -> Self {
+        self.read_only_token = Some(token.into());
+        self
+    }
+
+    /// Allow browsers to connect from `origin` (e.g. `http://localhost:5173`).
+    ///
+    /// Once an origin is allowed, requests with an `Origin` header not in the list are rejected.
+    /// Requests without an `Origin` header, which are not sent by browsers, are not affected.
+    #[must_use]
+    pub fn with_allowed_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+
+    /// Serve connections over TLS (`wss://`) using the given certificate and private key.
+    /// Plain connections are no longer accepted.
+    #[cfg(feature = "tls")]
+    #[must_use]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Listen on a port chosen by the OS when the port is already in use,
+    /// e.g. when running several instances of the app.
+    /// The port is reported by [`RemoteStreamServerStatus`].
+    #[must_use]
+    pub fn with_port_fallback(mut self) -> Self {
+        self.port_fallback = true;
+        self
+    }
+
+    /// Ping clients every `interval` and close the connection when nothing is received
+    /// from them for `timeout`.
+    #[must_use]
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some(Heartbeat { interval, timeout });
+        self
+    }
+
+    /// Don't ping clients, a dead connection is then only noticed when the OS reports it.
+    #[must_use]
+    pub fn without_heartbeat(mut self) -> Self {
+        self.heartbeat = None;
+        self
+    }
+
+    /// Set whether the server starts with the app, see [`ServerEnabled`].
+    #[must_use]
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
 }
 
 impl Default for RemoteStreamWebSocketPlugin {
@@ -70,6 +215,15 @@ impl Default for RemoteStreamWebSocketPlugin {
         Self {
             address: DEFAULT_ADDR,
             port: DEFAULT_PORT,
+            message_deflate: None,
+            token: None,
+            read_only_token: None,
+            allowed_origins: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            enabled: true,
+            port_fallback: false,
+            heartbeat: Some(DEFAULT_HEARTBEAT),
         }
     }
 }
@@ -78,7 +232,30 @@ impl Plugin for RemoteStreamWebSocketPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(HostAddress(self.address))
             .insert_resource(HostPort(self.port))
-            .add_systems(Startup, start_server);
+            .insert_resource(HostMessageDeflate(self.message_deflate))
+            .insert_resource(HostAccess {
+                token: self.token.clone(),
+                read_only_token: self.read_only_token.clone(),
+                allowed_origins: self.allowed_origins.clone(),
+            })
+            .insert_resource(HostPortFallback(self.port_fallback))
+            .insert_resource(HostHeartbeat(self.heartbeat))
+            .insert_resource(ServerEnabled(self.enabled))
+            .init_resource::<RemoteStreamServerStatus>()
+            .init_resource::<ServerEvents<RemoteStreamServerEvent>>()
+            .add_event::<RemoteStreamServerEvent>()
+            .add_systems(
+                Update,
+                (
+                    process_server_events::<RemoteStreamServerEvent>,
+                    update_server,
+                )
+                    .chain(),
+            )
+            .add_systems(Last, stop_server.run_if(on_event::<AppExit>));
+
+        #[cfg(feature = "tls")]
+        app.insert_resource(HostTls(self.tls.clone()));
     }
 }
 
@@ -88,10 +265,229 @@ pub struct HostAddress(pub IpAddr);
 #[derive(Debug, Resource, Reflect)]
 pub struct HostPort(pub u16);
 
-fn start_server(sender: Res<StreamSender>, address: Res<HostAddress>, remote_port: Res<HostPort>) {
+/// Whether to listen on a port chosen by the OS when [`HostPort`] is in use.
+#[derive(Debug, Resource, Reflect)]
+pub struct HostPortFallback(pub bool);
+
+/// The level of the application-level deflate offered to clients, `None` if it is disabled.
+/// See [`RemoteStreamWebSocketPlugin::with_message_deflate`].
+#[derive(Debug, Resource, Reflect)]
+pub struct HostMessageDeflate(pub Option<u32>);
+
+/// How dead connections are detected, `None` if clients are not pinged.
+#[derive(Debug, Resource, Reflect)]
+pub struct HostHeartbeat(pub Option<Heartbeat>);
+
+#[derive(Debug, Clone, Copy, Reflect)]
+pub struct Heartbeat {
+    /// Time between two pings sent to a client.
+    pub interval: Duration,
+    /// Time without receiving anything from a client after which its connection is lost.
+    pub timeout: Duration,
+}
+
+/// Access control of the server.
+#[derive(Debug, Resource, Clone)]
+pub struct HostAccess {
+    /// The token clients must provide to connect.
+    pub token: Option<String>,
+    /// The token granting [`ClientAccess::ReadOnly`], see
+    /// [`RemoteStreamWebSocketPlugin::with_read_only_token`].
+    pub read_only_token: Option<String>,
+    /// The origins browsers are allowed to connect from, empty allows every origin.
+    pub allowed_origins: Vec<String>,
+}
+
+/// The certificate and key used to serve connections over TLS, `None` if TLS is disabled.
+#[cfg(feature = "tls")]
+#[derive(Debug, Resource)]
+pub struct HostTls(pub Option<TlsConfig>);
+
+/// Whether the server is running. Set it to `false` to close all the connections
+/// and stop listening, and back to `true` to start the server again.
+#[derive(Debug, Resource, Reflect)]
+pub struct ServerEnabled(pub bool);
+
+/// Status of the server, updated from [`RemoteStreamServerEvent`]s.
+pub type RemoteStreamServerStatus = ServerStatus<SocketAddr>;
+
+/// Sent when the server status changes, see [`RemoteStreamServerStatus`].
+#[derive(Debug, Event, Clone)]
+pub enum RemoteStreamServerEvent {
+    /// The server is listening on this address.
+    Listening(SocketAddr),
+    /// The server could not listen on this address, e.g. the port is already in use.
+    BindFailed { address: SocketAddr, error: String },
+    /// The server stopped on an error.
+    Failed(String),
+    /// A WebSocket client connected.
+    ClientConnected(StreamClientId),
+    /// A WebSocket client disconnected.
+    ClientDisconnected(StreamClientId),
+}
+
+impl ServerEvent for RemoteStreamServerEvent {
+    type Address = SocketAddr;
+
+    fn update(&self, status: &mut RemoteStreamServerStatus) {
+        match self {
+            RemoteStreamServerEvent::Listening(address) => {
+                info!("Stream server listening on {address}");
+                status.listening = Some(*address);
+                status.last_error = None;
+            }
+            RemoteStreamServerEvent::BindFailed { address, error } => {
+                error!("Stream server failed to listen on {address}: {error}");
+                status.last_error = Some(error.clone());
+            }
+            RemoteStreamServerEvent::Failed(error) => {
+                status.listening = None;
+                status.last_error = Some(error.clone());
+            }
+            RemoteStreamServerEvent::ClientConnected(_) => status.clients += 1,
+            RemoteStreamServerEvent::ClientDisconnected(_) => {
+                status.clients = status.clients.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// The running server, dropping it stops the server.
+#[derive(Resource)]
+struct RunningServer {
+    /// Closed when dropped, which stops the server tasks.
+    _shutdown: Sender<()>,
+}
+
+/// Settings shared by all client connections.
+struct ServerSettings {
+    message_deflate: Option<Compression>,
+    access: HostAccess,
+    heartbeat: Option<Heartbeat>,
+    /// Whether responses are numbered, see [`SessionGracePeriod`].
+    sessions: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<futures_rustls::TlsAcceptor>,
+    /// Closed when the server is stopped.
+    shutdown: Receiver<()>,
+    events: Sender<RemoteStreamServerEvent>,
+    stats: StreamStats,
+}
+
+impl ServerSettings {
+    fn send_event(&self, event: RemoteStreamServerEvent) {
+        let _ = self.events.try_send(event);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_server(
+    mut commands: Commands,
+    sender: Res<StreamSender>,
+    address: Res<HostAddress>,
+    remote_port: Res<HostPort>,
+    port_fallback: Res<HostPortFallback>,
+    message_deflate: Res<HostMessageDeflate>,
+    access: Res<HostAccess>,
+    heartbeat: Res<HostHeartbeat>,
+    session_grace_period: Res<SessionGracePeriod>,
+    #[cfg(feature = "tls")] tls: Res<HostTls>,
+    enabled: Res<ServerEnabled>,
+    events: Res<ServerEvents<RemoteStreamServerEvent>>,
+    stats: Res<StreamStats>,
+    mut status: ResMut<RemoteStreamServerStatus>,
+) {
+    let changed = address.is_changed()
+        || remote_port.is_changed()
+        || port_fallback.is_changed()
+        || message_deflate.is_changed()
+        || access.is_changed()
+        || heartbeat.is_changed()
+        || session_grace_period.is_changed()
+        || enabled.is_changed();
+    #[cfg(feature = "tls")]
+    let changed = changed || tls.is_changed();
+
+    if !changed {
+        return;
+    }
+
+    commands.remove_resource::<RunningServer>();
+    status.listening = None;
+
+    if !enabled.0 {
+        return;
+    }
+
+    let (shutdown_sender, shutdown) = channel::bounded(1);
+    let settings = ServerSettings {
+        message_deflate: message_deflate.0.map(Compression::new),
+        access: access.clone(),
+        heartbeat: heartbeat.0,
+        sessions: session_grace_period.0.is_some(),
+        #[cfg(feature = "tls")]
+        tls: match tls.0.as_ref().map(TlsConfig::acceptor).transpose() {
+            Ok(acceptor) => acceptor,
+            Err(err) => {
+                let error = format!("Invalid TLS config: {err:#}");
+                error!("Failed to start the stream server: {error}");
+                let _ = events
+                    .sender
+                    .try_send(RemoteStreamServerEvent::Failed(error));
+                return;
+            }
+        },
+        shutdown,
+        events: events.sender.clone(),
+        stats: stats.clone(),
+    };
+
+    let address = SocketAddr::new(address.0, remote_port.0);
+    let (port_fallback, sender) = (port_fallback.0, sender.clone());
     IoTaskPool::get()
-        .spawn(server_main(address.0, remote_port.0, sender.clone()))
+        .spawn(async move {
+            let settings = Arc::new(settings);
+            if let Err(err) = server_main(address, port_fallback, sender, settings.clone()).await {
+                error!("Stream server on {address} stopped: {err}");
+                settings.send_event(RemoteStreamServerEvent::Failed(err.to_string()));
+            }
+        })
         .detach();
+
+    commands.insert_resource(RunningServer {
+        _shutdown: shutdown_sender,
+    });
+}
+
+fn stop_server(mut commands: Commands) {
+    commands.remove_resource::<RunningServer>();
+}
+
+/// Runs `future` until the server is stopped, returns `None` if it was stopped first.
+async fn until_stopped<T>(shutdown: &Receiver<()>, future: impl Future<Output = T>) -> Option<T> {
+    smol::future::or(async { Some(future.await) }, async {
+        let _ = shutdown.recv().await;
+        None
+    })
+    .await
+}
+
+/// Number of attempts to bind the listener while the address is in use.
+const BIND_ATTEMPTS: usize = 20;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+async fn bind(address: SocketAddr) -> io::Result<Async<TcpListener>> {
+    let mut attempts = 1;
+    loop {
+        match Async::<TcpListener>::bind(address) {
+            // The listener of a restarted server may still be shutting down
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && attempts < BIND_ATTEMPTS => {
+                attempts += 1;
+                Timer::after(BIND_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
 }
 
 struct TcpClient {
@@ -100,38 +496,79 @@ struct TcpClient {
 }
 
 async fn server_main(
-    address: IpAddr,
-    port: u16,
+    address: SocketAddr,
+    port_fallback: bool,
     request_sender: Sender<StreamMessage>,
+    settings: Arc<ServerSettings>,
 ) -> anyhow::Result<()> {
-    let listener = Async::<TcpListener>::bind((address, port))?;
-    let mut client_id: usize = 0;
-    loop {
-        let (stream, _) = listener.accept().await?;
-        client_id = client_id.wrapping_add(1);
+    let listener = match until_stopped(&settings.shutdown, bind(address)).await {
+        None => return Ok(()),
+        Some(Ok(listener)) => listener,
+        Some(Err(err)) => {
+            settings.send_event(RemoteStreamServerEvent::BindFailed {
+                address,
+                error: err.to_string(),
+            });
+
+            if !port_fallback || err.kind() != io::ErrorKind::AddrInUse {
+                return Ok(());
+            }
+
+            // Let the OS pick a free port
+            Async::<TcpListener>::bind(SocketAddr::new(address.ip(), 0))?
+        }
+    };
+    settings.send_event(RemoteStreamServerEvent::Listening(
+        listener.get_ref().local_addr()?,
+    ));
+    while let Some(accepted) = until_stopped(&settings.shutdown, listener.accept()).await {
+        let (stream, _) = accepted?;
         let client = TcpClient {
-            id: StreamClientId(client_id),
+            id: StreamClientId::next(),
             stream,
         };
         let request_sender = request_sender.clone();
+        let settings = settings.clone();
         IoTaskPool::get()
             .spawn(async move {
-                let _ = handle_client(client, request_sender).await;
+                let shutdown = settings.shutdown.clone();
+                let _ =
+                    until_stopped(&shutdown, handle_client(client, request_sender, settings)).await;
             })
             .detach();
     }
+
+    Ok(())
 }
 
 async fn handle_client(
     client: TcpClient,
     request_sender: Sender<StreamMessage>,
+    settings: Arc<ServerSettings>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = settings.tls.clone() {
+        let stream = acceptor.accept(client.stream).await?;
+        return serve_connection(client.id, stream, request_sender, settings).await;
+    }
+
+    serve_connection(client.id, client.stream, request_sender, settings).await
+}
+
+async fn serve_connection(
+    client_id: StreamClientId,
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request_sender: Sender<StreamMessage>,
+    settings: Arc<ServerSettings>,
 ) -> anyhow::Result<()> {
     http1::Builder::new()
         .keep_alive(true)
         .timer(SmolTimer::new())
         .serve_connection(
-            FuturesIo::new(client.stream),
-            service::service_fn(|request| process_request(request, &request_sender, client.id)),
+            FuturesIo::new(stream),
+            service::service_fn(|request| {
+                process_request(request, &request_sender, client_id, &settings)
+            }),
         )
         .with_upgrades()
         .await?;
@@ -143,23 +580,84 @@ async fn process_request(
     mut request: Request<Incoming>,
     request_sender: &Sender<StreamMessage>,
     client_id: StreamClientId,
+    settings: &Arc<ServerSettings>,
 ) -> anyhow::Result<Response<Full<Bytes>>> {
-    let default_origin = HeaderValue::from_static("");
-    let origin = request.headers().get(ORIGIN).unwrap_or(&default_origin);
+    let origin = request.headers().get(ORIGIN).cloned();
+
+    if let Err(err) = settings.access.check_origin(origin.as_ref()) {
+        return error_response(StatusCode::FORBIDDEN, err);
+    }
 
     if request.method() == Method::OPTIONS {
-        let response = Response::builder()
+        let mut response = Response::builder()
             .status(200)
             .header(ACCESS_CONTROL_ALLOW_METHODS, "*")
-            .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+            .header(ACCESS_CONTROL_ALLOW_HEADERS, "*")
             .header(ACCESS_CONTROL_MAX_AGE, "86400")
-            .body(Full::new(Bytes::new()))?;
+            .header(VARY, ORIGIN.as_str());
+        if let Some(origin) = &origin {
+            response = response.header(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
 
-        return Ok(response);
+        return Ok(response.body(Full::new(Bytes::new()))?);
+    }
+
+    if request.method() == Method::POST {
+        let access = match settings
+            .access
+            .check_token(request.headers(), request.uri())
+        {
+            Ok(access) => access,
+            Err(err) => return error_response(StatusCode::UNAUTHORIZED, err),
+        };
+
+        let response =
+            match process_one_shot_request(request, request_sender, client_id, access).await {
+                Ok(response) => response,
+                Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+            };
+
+        let mut builder = Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, "application/json")
+            .header(VARY, ORIGIN.as_str());
+        if let Some(origin) = &origin {
+            builder = builder.header(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+
+        return Ok(builder.body(Full::new(serde_json::to_vec(&response)?.into()))?);
     }
 
     if hyper_tungstenite::is_upgrade_request(&request) {
-        let (response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+        let access = match settings
+            .access
+            .check_token(request.headers(), request.uri())
+        {
+            Ok(access) => access,
+            Err(err) => return error_response(StatusCode::UNAUTHORIZED, err),
+        };
+
+        let (mut response, websocket) = hyper_tungstenite::upgrade(&mut request, None)?;
+
+        let format = match MessageFormat::from_request(&request, settings) {
+            Ok(format) => format,
+            Err(err) => {
+                let response = serde_json::to_string(&BrpError {
+                    code: error_codes::INVALID_REQUEST,
+                    message: format!("{err}"),
+                    data: None,
+                })?;
+
+                return Ok(Response::new(Full::new(response.into_bytes().into())));
+            }
+        };
+
+        if format.encoding.is_subprotocol_requested(&request) {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(format.encoding.name()),
+            );
+        }
 
         let body = match validate_websocket_request(&request) {
             Ok(body) => body,
@@ -174,12 +672,24 @@ async fn process_request(
             }
         };
 
+        let session = query_param(request.uri(), SESSION_QUERY_KEY)
+            .and_then(|value| urlencoding::decode(value).ok())
+            .map(|token| SessionResume {
+                token: token.into_owned(),
+                last_seq: query_param(request.uri(), LAST_SEQ_QUERY_KEY)
+                    .and_then(|value| value.parse().ok()),
+            });
+
         IoTaskPool::get()
             .spawn(process_websocket_stream(
                 websocket,
                 request_sender.clone(),
                 body,
+                session,
+                access,
                 client_id,
+                format,
+                settings.clone(),
             ))
             .detach();
 
@@ -192,84 +702,400 @@ async fn process_request(
         data: None,
     })?;
 
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(400)
-        .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
-        .body(Full::new(response_body.into_bytes().into()))
-        .unwrap();
+        .header(VARY, ORIGIN.as_str());
+    if let Some(origin) = &origin {
+        response = response.header(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+
+    Ok(response.body(Full::new(response_body.into_bytes().into()))?)
+}
+
+/// Maximum size of a plain HTTP request body.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
 
-    return Ok(response);
+/// Forwards a POSTed JSON-RPC request to the matching [`OneShotMethods`](crate::OneShotMethods)
+/// handler and waits for its response.
+async fn process_one_shot_request(
+    request: Request<Incoming>,
+    request_sender: &Sender<StreamMessage>,
+    client_id: StreamClientId,
+    access: ClientAccess,
+) -> anyhow::Result<BrpResponse> {
+    let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to read body: {err}"))?
+        .to_bytes();
+
+    let request = validate_request(serde_json::from_slice::<BrpRequest>(&body)?)?;
+
+    let (response_sender, response_receiver) = channel::bounded(1);
+    request_sender
+        .send(StreamMessage {
+            client_id,
+            kind: StreamMessageKind::Request(request, access, response_sender),
+        })
+        .await?;
+
+    Ok(response_receiver.recv().await?)
+}
+
+fn error_response(status: StatusCode, err: anyhow::Error) -> anyhow::Result<Response<Full<Bytes>>> {
+    let response_body = serde_json::to_string(&BrpError {
+        code: error_codes::INVALID_REQUEST,
+        message: format!("{err}"),
+        data: None,
+    })?;
+
+    Ok(Response::builder()
+        .status(status)
+        .body(Full::new(response_body.into_bytes().into()))?)
 }
 
+const TOKEN_QUERY_KEY: &str = "token";
+
+impl HostAccess {
+    fn check_origin(&self, origin: Option<&HeaderValue>) -> anyhow::Result<()> {
+        let Some(origin) = origin else {
+            return Ok(());
+        };
+
+        if self.allowed_origins.is_empty()
+            || self
+                .allowed_origins
+                .iter()
+                .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+        {
+            return Ok(());
+        }
+
+        anyhow::bail!("Origin is not allowed")
+    }
+
+    /// Returns the access granted by the token of the request.
+    fn check_token(&self, headers: &HeaderMap, uri: &Uri) -> anyhow::Result<ClientAccess> {
+        if self.token.is_none() && self.read_only_token.is_none() {
+            return Ok(ClientAccess::ReadWrite);
+        }
+
+        let from_header = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|value| value.trim().to_owned());
+        let provided = match from_header {
+            Some(provided) => Some(provided),
+            None => query_param(uri, TOKEN_QUERY_KEY)
+                .and_then(|value| urlencoding::decode(value).ok())
+                .map(|value| value.into_owned()),
+        };
+
+        let Some(provided) = provided else {
+            anyhow::bail!("Missing token")
+        };
+        let matches = |token: &Option<String>| {
+            token
+                .as_ref()
+                .is_some_and(|token| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+        };
+
+        if matches(&self.token) {
+            Ok(ClientAccess::ReadWrite)
+        } else if matches(&self.read_only_token) {
+            Ok(ClientAccess::ReadOnly)
+        } else {
+            anyhow::bail!("Invalid token")
+        }
+    }
+}
+
+/// Compares two byte slices without short-circuiting on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_websocket_stream(
     ws: HyperWebsocket,
     request_sender: Sender<StreamMessage>,
-    request: BrpRequest,
+    request: Option<BrpRequest>,
+    session: Option<SessionResume>,
+    access: ClientAccess,
     client_id: StreamClientId,
+    format: MessageFormat,
+    settings: Arc<ServerSettings>,
 ) -> anyhow::Result<()> {
     let ws = ws.await?;
+    settings.send_event(RemoteStreamServerEvent::ClientConnected(client_id));
 
     let (write_stream, read_stream) = ws.split();
 
-    let (result_sender, result_receiver) = channel::bounded(32);
+    let (connection_sender, connection_receiver) = channel::bounded(32);
 
     IoTaskPool::get()
-        .spawn(send_stream_response(write_stream, result_receiver))
+        .spawn(send_stream_response(
+            write_stream,
+            connection_receiver,
+            format,
+            settings.clone(),
+        ))
         .detach();
 
-    send_stream_message(
+    let result = send_stream_message(
         read_stream,
         request_sender.clone(),
         request,
-        result_sender,
+        session,
+        access,
+        connection_sender,
         client_id,
+        &settings,
     )
-    .await?;
+    .await;
 
-    Ok(())
+    settings.send_event(RemoteStreamServerEvent::ClientDisconnected(client_id));
+
+    result
 }
 
 const QUERY_KEY: &str = "body";
+const ENCODING_QUERY_KEY: &str = "encoding";
+const MESSAGE_DEFLATE_QUERY_KEY: &str = "message_deflate";
+const SESSION_QUERY_KEY: &str = "session";
+const LAST_SEQ_QUERY_KEY: &str = "last_seq";
 
-fn validate_websocket_request(request: &Request<Incoming>) -> anyhow::Result<BrpRequest> {
-    let body = request
-        .uri()
-        .query()
-        .and_then(|query| {
-            // Simple query string parsing
-            for pair in query.split('&') {
-                let mut it = pair.split('=').take(2);
-                match (it.next(), it.next()) {
-                    (Some(k), Some(v)) if k == QUERY_KEY => return Some(v),
-                    _ => {}
-                };
-            }
-            None
+fn query_param<'a>(uri: &'a Uri, key: &str) -> Option<&'a str> {
+    uri.query().and_then(|query| {
+        // Simple query string parsing
+        for pair in query.split('&') {
+            let mut it = pair.split('=').take(2);
+            match (it.next(), it.next()) {
+                (Some(k), Some(v)) if k == key => return Some(v),
+                _ => {}
+            };
+        }
+        None
+    })
+}
+
+/// Encoding of the messages sent over the WebSocket.
+///
+/// Negotiated with the `encoding` query parameter or the `Sec-WebSocket-Protocol` header.
+/// Incoming text frames are always parsed as JSON and binary frames as MessagePack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Encoding::Json, Encoding::MessagePack]
+            .into_iter()
+            .find(|encoding| encoding.name() == name)
+    }
+
+    fn from_request(request: &Request<Incoming>) -> anyhow::Result<Self> {
+        if let Some(name) = query_param(request.uri(), ENCODING_QUERY_KEY) {
+            return Self::from_name(name)
+                .ok_or_else(|| anyhow::anyhow!("Unsupported encoding `{name}`"));
+        }
+
+        Ok(requested_subprotocols(request)
+            .find_map(Self::from_name)
+            .unwrap_or_default())
+    }
+
+    fn is_subprotocol_requested(self, request: &Request<Incoming>) -> bool {
+        requested_subprotocols(request).any(|protocol| protocol == self.name())
+    }
+
+    fn encode(self, response: &impl Serialize) -> anyhow::Result<Message> {
+        match self {
+            Encoding::Json => Ok(Message::text(serde_json::to_string(response)?)),
+            Encoding::MessagePack => Ok(Message::binary(rmp_serde::to_vec_named(response)?)),
+        }
+    }
+}
+
+/// How messages sent to a client are encoded and deflated.
+#[derive(Debug, Clone, Copy)]
+struct MessageFormat {
+    encoding: Encoding,
+    message_deflate: Option<Compression>,
+}
+
+impl MessageFormat {
+    fn from_request(
+        request: &Request<Incoming>,
+        settings: &ServerSettings,
+    ) -> anyhow::Result<Self> {
+        // Clients can always offer message deflate, it is only used if the server enables it
+        let message_deflate = match query_param(request.uri(), MESSAGE_DEFLATE_QUERY_KEY) {
+            None => None,
+            Some("raw") => settings.message_deflate,
+            Some(name) => anyhow::bail!("Unsupported message deflate `{name}`, expected `raw`"),
+        };
+
+        Ok(Self {
+            encoding: Encoding::from_request(request)?,
+            message_deflate,
         })
-        .ok_or_else(|| anyhow::anyhow!("Missing body"))?;
+    }
+
+    fn encode(self, response: &impl Serialize) -> anyhow::Result<Message> {
+        let message = self.encoding.encode(response)?;
+
+        let Some(level) = self.message_deflate else {
+            return Ok(message);
+        };
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), level);
+        encoder.write_all(&message.into_data())?;
+
+        Ok(Message::binary(encoder.finish()?))
+    }
+}
+
+/// A response along with its position in the connection, starting at 1.
+#[derive(Serialize)]
+struct SequencedResponse<'a> {
+    #[serde(flatten)]
+    response: &'a BrpResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+}
+
+fn requested_subprotocols(request: &Request<Incoming>) -> impl Iterator<Item = &str> {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// Returns the stream request of the `body` query parameter,
+/// `None` if it is sent as the first message instead.
+fn validate_websocket_request(request: &Request<Incoming>) -> anyhow::Result<Option<BrpRequest>> {
+    let Some(body) = query_param(request.uri(), QUERY_KEY) else {
+        return Ok(None);
+    };
 
     let body = urlencoding::decode(body)?.into_owned();
 
-    match serde_json::from_str::<BrpRequest>(&body) {
-        Ok(req) => {
-            if req.jsonrpc != "2.0" {
-                anyhow::bail!("JSON-RPC request requires `\"jsonrpc\": \"2.0\"`")
+    validate_request(serde_json::from_str::<BrpRequest>(&body)?).map(Some)
+}
+
+fn parse_message(message: Message) -> anyhow::Result<Option<Value>> {
+    match message {
+        Message::Text(text) => Ok(Some(serde_json::from_str::<Value>(&text)?)),
+        Message::Binary(bytes) => Ok(Some(rmp_serde::from_slice::<Value>(&bytes)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the next text or binary message, `None` once the client closed the connection
+/// or the server is stopped.
+/// Fails when the connection is lost, e.g. nothing was received within the heartbeat timeout.
+async fn next_message(
+    stream: &mut SplitStream<HyperWebsocketStream>,
+    settings: &ServerSettings,
+) -> anyhow::Result<Option<Value>> {
+    loop {
+        let next = smol::future::or(async { Ok(stream.next().await) }, async {
+            match settings.heartbeat {
+                Some(heartbeat) => {
+                    Timer::after(heartbeat.timeout).await;
+                    Err(anyhow::anyhow!("Heartbeat timed out"))
+                }
+                None => std::future::pending().await,
             }
+        });
 
-            Ok(req)
+        let Some(message) = until_stopped(&settings.shutdown, next).await else {
+            return Ok(None);
+        };
+
+        // Pings and pongs are only used to keep the connection alive
+        match message? {
+            Some(Ok(Message::Close(_))) => return Ok(None),
+            Some(Ok(message)) => {
+                if let Some(msg) = parse_message(message)? {
+                    return Ok(Some(msg));
+                }
+            }
+            Some(Err(err)) => return Err(err.into()),
+            None => anyhow::bail!("Connection closed without a close frame"),
         }
-        Err(err) => anyhow::bail!(err),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn send_stream_message(
     mut stream: SplitStream<HyperWebsocketStream>,
     sender: Sender<StreamMessage>,
-    request: BrpRequest,
-    result_sender: Sender<BrpResponse>,
+    request: Option<BrpRequest>,
+    session: Option<SessionResume>,
+    access: ClientAccess,
+    connection: Sender<OutgoingMessage>,
     client_id: StreamClientId,
+    settings: &ServerSettings,
 ) -> anyhow::Result<()> {
-    let _ = sender
+    let request = match request {
+        Some(request) => request,
+        // Without a `body` query parameter, the first message either opens a multiplexed stream
+        // or is the stream request
+        None => {
+            let Some(first) = next_message(&mut stream, settings).await? else {
+                return Ok(());
+            };
+
+            if first.get("method").and_then(Value::as_str) == Some(mux::OPEN_METHOD) {
+                let mut multiplexer = Multiplexer::new(sender, connection, client_id, access);
+                multiplexer.handle(first).await?;
+                return send_multiplexed_messages(stream, multiplexer, settings).await;
+            }
+
+            match serde_json::from_value::<BrpRequest>(first)
+                .map_err(anyhow::Error::from)
+                .and_then(validate_request)
+            {
+                Ok(request) => request,
+                Err(err) => {
+                    let response = BrpResponse::new(
+                        None,
+                        Err(BrpError {
+                            code: error_codes::INVALID_REQUEST,
+                            message: format!("{err}"),
+                            data: None,
+                        }),
+                    );
+                    let _ = connection
+                        .send(OutgoingMessage::Response(client_id, response))
+                        .await;
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    // The connection is closed once the server removes the stream
+    let (result_sender, forward) = mux::forward_responses(client_id, connection);
+    IoTaskPool::get().spawn(forward).detach();
+
+    sender
         .send(StreamMessage {
             client_id,
             kind: StreamMessageKind::Connect(
@@ -278,43 +1104,242 @@ async fn send_stream_message(
                     method: request.method,
                     params: request.params,
                     sender: result_sender,
+                    session,
+                    access,
                 },
             ),
         })
         .await?;
-    while let Some(message) = stream.next().await {
-        match message {
-            Ok(Message::Text(text)) => {
-                let msg = serde_json::from_str::<Value>(&text)?;
-                let _ = sender
+    let kind = loop {
+        match next_message(&mut stream, settings).await {
+            Ok(Some(msg)) => {
+                sender
                     .send(StreamMessage {
                         client_id,
                         kind: StreamMessageKind::Data(msg),
                     })
                     .await?;
             }
-            Ok(Message::Close(_)) | Err(_) => return Ok(()),
-            _ => {}
+            Ok(None) => break StreamMessageKind::Disconnect,
+            Err(err) => {
+                debug!("Client {client_id:?} connection lost: {err}");
+                break StreamMessageKind::ConnectionLost;
+            }
         }
-    }
-    let _ = sender
-        .send(StreamMessage {
-            client_id,
-            kind: StreamMessageKind::Disconnect,
-        })
-        .await?;
+    };
+    sender.send(StreamMessage { client_id, kind }).await?;
 
     Ok(())
 }
 
+async fn send_multiplexed_messages(
+    mut stream: SplitStream<HyperWebsocketStream>,
+    mut multiplexer: Multiplexer,
+    settings: &ServerSettings,
+) -> anyhow::Result<()> {
+    loop {
+        let result = smol::future::or(
+            async {
+                match next_message(&mut stream, settings).await {
+                    Ok(Some(msg)) => Some(Ok(msg)),
+                    _ => None,
+                }
+            },
+            async { Some(Err(multiplexer.next_closed().await)) },
+        )
+        .await;
+
+        let handled = match result {
+            Some(Ok(msg)) => multiplexer.handle(msg).await,
+            // The server removed the stream, e.g. its handler failed
+            Some(Err((id, client_id))) => multiplexer.reap(id, client_id).await,
+            None => break,
+        };
+        if handled.is_err() {
+            break;
+        }
+    }
+
+    // Streams outlive a failed connection otherwise
+    multiplexer.close_all().await
+}
+
 async fn send_stream_response(
     mut stream: SplitSink<HyperWebsocketStream, Message>,
-    result_receiver: Receiver<BrpResponse>,
+    result_receiver: Receiver<OutgoingMessage>,
+    format: MessageFormat,
+    settings: Arc<ServerSettings>,
 ) -> anyhow::Result<()> {
-    while let Ok(response) = result_receiver.recv().await {
-        let response = serde_json::to_string(&response)?;
-        stream.send(Message::text(response)).await?;
+    let mut pings = settings
+        .heartbeat
+        .map(|heartbeat| Timer::interval(heartbeat.interval));
+    let mut seq = 0;
+
+    loop {
+        let next = smol::future::or(async { Some(result_receiver.recv().await) }, async {
+            match &mut pings {
+                Some(pings) => {
+                    pings.next().await;
+                    None
+                }
+                None => std::future::pending().await,
+            }
+        });
+
+        match until_stopped(&settings.shutdown, next).await {
+            Some(Some(Ok(OutgoingMessage::Response(client_id, response)))) => {
+                seq += 1;
+                let start = Instant::now();
+                let message = format.encode(&SequencedResponse {
+                    response: &response,
+                    seq: settings.sessions.then_some(seq),
+                })?;
+                settings
+                    .stats
+                    .record_bytes_sent(client_id, message.len(), start.elapsed());
+                stream.send(message).await?;
+            }
+            Some(Some(Ok(OutgoingMessage::Notification(notification)))) => {
+                stream.send(format.encode(&notification)?).await?;
+            }
+            Some(None) => stream.send(Message::Ping(Vec::new())).await?,
+            Some(Some(Err(_))) | None => break,
+        }
+    }
+
+    if settings.shutdown.is_closed() {
+        stream
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: Cow::Borrowed("Server stopped"),
+            })))
+            .await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+    use serde_json::json;
+
+    use super::*;
+
+    fn access() -> HostAccess {
+        HostAccess {
+            token: Some("secret".into()),
+            read_only_token: Some("viewer".into()),
+            allowed_origins: vec!["http://localhost:5173".into()],
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn token_is_not_required_by_default() {
+        let access = HostAccess {
+            token: None,
+            read_only_token: None,
+            allowed_origins: Vec::new(),
+        };
+        let access = access.check_token(&HeaderMap::new(), &Uri::from_static("/"));
+
+        assert_eq!(access.unwrap(), ClientAccess::ReadWrite);
+    }
+
+    #[test]
+    fn missing_or_wrong_tokens_are_rejected() {
+        let access = access();
+
+        assert!(access
+            .check_token(&HeaderMap::new(), &Uri::from_static("/"))
+            .is_err());
+        assert!(access
+            .check_token(&bearer("wrong"), &Uri::from_static("/"))
+            .is_err());
+        assert!(access
+            .check_token(&HeaderMap::new(), &Uri::from_static("/?token=wrong"))
+            .is_err());
+    }
+
+    #[test]
+    fn tokens_are_read_from_the_header_or_the_query() {
+        let access = access();
+
+        let from_header = access.check_token(&bearer("secret"), &Uri::from_static("/"));
+        assert_eq!(from_header.unwrap(), ClientAccess::ReadWrite);
+        let from_query = access.check_token(
+            &HeaderMap::new(),
+            &Uri::from_static("/?body=x&token=secret"),
+        );
+        assert_eq!(from_query.unwrap(), ClientAccess::ReadWrite);
+    }
+
+    #[test]
+    fn read_only_token_grants_read_only_access() {
+        let access = access();
+
+        let from_header = access.check_token(&bearer("viewer"), &Uri::from_static("/"));
+        assert_eq!(from_header.unwrap(), ClientAccess::ReadOnly);
+        let from_query = access.check_token(&HeaderMap::new(), &Uri::from_static("/?token=viewer"));
+        assert_eq!(from_query.unwrap(), ClientAccess::ReadOnly);
+    }
+
+    #[test]
+    fn only_allowed_origins_are_accepted() {
+        let access = access();
+
+        assert!(access
+            .check_origin(Some(&HeaderValue::from_static("http://localhost:5173")))
+            .is_ok());
+        assert!(access
+            .check_origin(Some(&HeaderValue::from_static("https://example.com")))
+            .is_err());
+        // Requests without an origin are not sent by browsers
+        assert!(access.check_origin(None).is_ok());
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_slices() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn deflated_messages_inflate_to_the_encoded_message() {
+        let response = BrpResponse::new(Some(json!(1)), Ok(json!({ "entities": [1, 2, 3] })));
+
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let plain = MessageFormat {
+                encoding,
+                message_deflate: None,
+            }
+            .encode(&response)
+            .unwrap();
+            let deflated = MessageFormat {
+                encoding,
+                message_deflate: Some(Compression::new(6)),
+            }
+            .encode(&response)
+            .unwrap();
+            assert!(deflated.is_binary());
+
+            let mut inflated = Vec::new();
+            DeflateDecoder::new(&deflated.into_data()[..])
+                .read_to_end(&mut inflated)
+                .unwrap();
+            assert_eq!(inflated, plain.into_data());
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{thread, time::Duration};
+
+use bevy::{ecs::system::InRef, prelude::*, remote::BrpPayload};
+use bevy_remote_stream::{
+    local::LocalStreamClient, ClientAccess, RemoteStreamHandlersBuilder, RemoteStreamPlugin,
+    SessionResume, StreamHandlerInput,
+};
+
+const METHOD: &str = "test/stream";
+
+/// Number of times the handlers of the test stream ran.
+#[derive(Resource, Default)]
+struct Runs {
+    disconnects: usize,
+    resyncs: usize,
+}
+
+fn app(grace_period: Duration) -> App {
+    let mut app = App::new();
+    app.init_resource::<Runs>().add_plugins((
+        TaskPoolPlugin::default(),
+        RemoteStreamPlugin::default()
+            .with_method(
+                METHOD,
+                RemoteStreamHandlersBuilder::new(|_: InRef<StreamHandlerInput>| None)
+                    .on_disconnect(|_: InRef<StreamHandlerInput>, mut runs: ResMut<Runs>| {
+                        runs.disconnects += 1;
+                    })
+                    .on_resync(|_: InRef<StreamHandlerInput>, mut runs: ResMut<Runs>| {
+                        runs.resyncs += 1;
+                    }),
+            )
+            .with_session_resume(grace_period),
+    ));
+    app
+}
+
+fn runs(app: &App) -> &Runs {
+    app.world().resource::<Runs>()
+}
+
+/// Returns the session of the first response of `client` and whether the stream was resumed.
+fn session(client: &LocalStreamClient) -> (String, bool) {
+    let response = client.try_recv().unwrap();
+    let BrpPayload::Result(result) = response.payload else {
+        panic!("Unexpected payload {:?}", response.payload);
+    };
+    (
+        result["session"].as_str().unwrap().to_owned(),
+        result["resumed"].as_bool().unwrap(),
+    )
+}
+
+/// Opens a stream with a session and loses its connection.
+fn lost_session(app: &mut App, access: ClientAccess) -> String {
+    let client = LocalStreamClient::connect_with_access(app.world(), METHOD, None, access).unwrap();
+    app.update();
+    let (token, resumed) = session(&client);
+    assert!(!resumed);
+
+    client.lose_connection().unwrap();
+    app.update();
+
+    token
+}
+
+fn resume(
+    app: &App,
+    token: &str,
+    last_seq: Option<u64>,
+    access: ClientAccess,
+) -> LocalStreamClient {
+    let session = SessionResume {
+        token: token.to_owned(),
+        last_seq,
+    };
+    LocalStreamClient::resume(app.world(), METHOD, None, access, session).unwrap()
+}
+
+#[test]
+fn streams_are_resumed_within_the_grace_period() {
+    let mut app = app(Duration::from_secs(60));
+    let token = lost_session(&mut app, ClientAccess::ReadWrite);
+
+    // The session reply was the only response
+    let client = resume(&app, &token, Some(1), ClientAccess::ReadWrite);
+    app.update();
+
+    assert_eq!(session(&client), (token, true));
+    assert_eq!(runs(&app).disconnects, 0);
+    assert_eq!(runs(&app).resyncs, 0);
+
+    drop(client);
+    app.update();
+    assert_eq!(runs(&app).disconnects, 1);
+}
+
+#[test]
+fn streams_are_not_resumed_with_another_access() {
+    let mut app = app(Duration::from_secs(60));
+    let token = lost_session(&mut app, ClientAccess::ReadWrite);
+
+    let client = resume(&app, &token, Some(1), ClientAccess::ReadOnly);
+    app.update();
+
+    // A new stream is opened instead
+    let (new_token, resumed) = session(&client);
+    assert!(!resumed);
+    assert_ne!(new_token, token);
+    assert_eq!(runs(&app).disconnects, 0);
+}
+
+#[test]
+fn streams_are_closed_after_the_grace_period() {
+    let mut app = app(Duration::from_millis(50));
+    let token = lost_session(&mut app, ClientAccess::ReadWrite);
+    assert_eq!(runs(&app).disconnects, 0);
+
+    thread::sleep(Duration::from_millis(100));
+    app.update();
+    assert_eq!(runs(&app).disconnects, 1);
+
+    let client = resume(&app, &token, Some(1), ClientAccess::ReadWrite);
+    app.update();
+    let (new_token, resumed) = session(&client);
+    assert!(!resumed);
+    assert_ne!(new_token, token);
+}
+
+#[test]
+fn streams_missing_responses_are_resynced() {
+    let mut app = app(Duration::from_secs(60));
+    let token = lost_session(&mut app, ClientAccess::ReadWrite);
+
+    // The client did not receive the session reply
+    let client = resume(&app, &token, Some(0), ClientAccess::ReadWrite);
+    app.update();
+    assert_eq!(session(&client), (token.clone(), true));
+    assert_eq!(runs(&app).resyncs, 1);
+
+    client.lose_connection().unwrap();
+    app.update();
+    let client = resume(&app, &token, None, ClientAccess::ReadWrite);
+    app.update();
+    assert_eq!(session(&client), (token, true));
+    assert_eq!(runs(&app).resyncs, 2);
+}
@@ -0,0 +1,36 @@
+import { CreateSlice } from '@/store';
+import { ResourceMutation } from '@/websocket/createWsSlice';
+import { ResourceId, ResourceInfo } from './useResources';
+
+export type ResourcesSlice = {
+  resources: Map<ResourceId, ResourceInfo>;
+  updateResource: (id: ResourceId, mutation: ResourceMutation) => void;
+};
+
+export const createResourcesSlice: CreateSlice<ResourcesSlice> = (
+  set,
+  get
+) => ({
+  resources: new Map(),
+  updateResource: (id, mutation) => {
+    const resources = get().resources;
+    if (mutation.kind === 'add') {
+      resources.set(id, {
+        name: mutation.name,
+        reflected: mutation.reflected,
+        value: mutation.value,
+      });
+    } else if (mutation.kind === 'change') {
+      const resource = resources.get(id);
+      if (!resource) {
+        return;
+      }
+
+      resources.set(id, { ...resource, value: mutation.value });
+    } else {
+      resources.delete(id);
+    }
+
+    set({ resources: new Map(resources) });
+  },
+});
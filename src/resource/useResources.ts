@@ -0,0 +1,16 @@
+import { useStore } from '@/store';
+import { TValue, TypeName } from '../type-registry/useTypeRegistry';
+
+export type ResourceName = TypeName;
+export type ResourceValue = TValue;
+export type ResourceId = number;
+
+export type ResourceInfo = {
+  name: ResourceName;
+  reflected: boolean;
+  value?: ResourceValue;
+};
+
+export function useResource(id: ResourceId) {
+  return useStore((state) => state.resources.get(id));
+}
@@ -16,6 +16,10 @@ import {
   ComponentsSlice,
   createComponentsSlice,
 } from './component/createComponentsSlice';
+import {
+  createResourcesSlice,
+  ResourcesSlice,
+} from './resource/createResourcesSlice';
 
 export type CreateSlice<T> = StateCreator<SharedSlice, [], [], T>;
 
@@ -23,6 +27,7 @@ export type SharedSlice = TypeRegistrySlice &
   WsSlice &
   ComponentsSlice &
   EntitiesSlice &
+  ResourcesSlice &
   InspectorSlice;
 
 export const useStore = create<SharedSlice>()((...a) => ({
@@ -30,5 +35,6 @@ export const useStore = create<SharedSlice>()((...a) => ({
   ...createTypeRegistrySlice(...a),
   ...createEntitiesSlice(...a),
   ...createComponentsSlice(...a),
+  ...createResourcesSlice(...a),
   ...createInspectorSlice(...a),
 }));
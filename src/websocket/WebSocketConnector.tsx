@@ -3,6 +3,7 @@ import { useStore } from '@/store';
 import { useEffect } from 'react';
 import useWebSocket from 'react-use-websocket';
 import { WEB_SOCKET_MESSAGE_ID } from './useWs';
+import { MESSAGE_DEFLATE_SUPPORTED } from './createWsSlice';
 
 export function WebsocketConnector() {
   const url = useStore((state) => state.url) || null;
@@ -19,6 +20,8 @@ export function WebsocketConnector() {
           jsonrpc: '2.0',
           id: WEB_SOCKET_MESSAGE_ID,
         }),
+        // Servers without message deflate ignore it and send text frames
+        ...(MESSAGE_DEFLATE_SUPPORTED ? { message_deflate: 'raw' } : {}),
       },
       onError: (_e) => {
         const isManuallyConnect = useStore.getState().isManuallyConnect;
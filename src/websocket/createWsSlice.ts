@@ -11,6 +11,7 @@ import {
   ComponentValue,
 } from '@/component/useComponents';
 import { EntityId } from '@/entity/useEntity';
+import { ResourceId, ResourceValue } from '@/resource/useResources';
 export type WsSlice = {
   url?: string;
   readyState: ReadyState;
@@ -22,6 +23,7 @@ export type WsSlice = {
   initSendMessage: (fn: SendJsonMessage) => void;
   setReadyState: (readyState: ReadyState) => void;
   onMessage: (message: MessageEvent<any>) => void;
+  handleEvent: (event: WsEvent) => void;
   commandCallbacks: Map<string, (data: WsEvent) => void>;
   shouldReconnect: boolean;
   hasConnected: boolean;
@@ -32,6 +34,31 @@ let sendMessageInteral: SendJsonMessage = () => {
   console.error('sendMessage not initialized');
 };
 
+// Whether the browser can inflate the messages of a server with message deflate enabled,
+// they are then binary frames holding the raw deflate stream of the JSON message.
+export const MESSAGE_DEFLATE_SUPPORTED = (() => {
+  try {
+    new DecompressionStream('deflate-raw');
+    return true;
+  } catch {
+    return false;
+  }
+})();
+
+// Binary frames are inflated asynchronously, messages are chained to be handled in order.
+let pendingMessage: Promise<void> = Promise.resolve();
+
+async function messageText(data: string | Blob): Promise<string> {
+  if (typeof data === 'string') {
+    return data;
+  }
+
+  const inflated = data
+    .stream()
+    .pipeThrough(new DecompressionStream('deflate-raw'));
+  return new Response(inflated).text();
+}
+
 let id = 10;
 export const createWsSlice: StateCreator<SharedSlice, [], [], WsSlice> = (
   set,
@@ -77,19 +104,19 @@ export const createWsSlice: StateCreator<SharedSlice, [], [], WsSlice> = (
       set({
         hasConnected: true,
         shouldReconnect: true,
-        childParentMap: new Map(),
-        entities: new Map(),
-        registry: new Map(),
-        componentNameToIdMap: new Map(),
-        components: new Map(),
-        inspectingEntity: null,
+        ...emptyWorldState(),
       });
       localStorage.setItem('ws_url', get().url!);
     }
   },
   onMessage: (message) => {
+    pendingMessage = pendingMessage
+      .then(() => messageText(message.data))
+      .then((text) => get().handleEvent(JSON.parse(text) as WsEvent))
+      .catch((e) => console.error(e));
+  },
+  handleEvent: (event) => {
     try {
-      const event = JSON.parse(message.data) as WsEvent;
       if (event.id === null) {
         return;
       }
@@ -115,12 +142,16 @@ export const createWsSlice: StateCreator<SharedSlice, [], [], WsSlice> = (
       }
 
       for (const item of event.result) {
-        if (item.kind === 'type_registry') {
+        if (item.kind === 'reset') {
+          set(emptyWorldState());
+        } else if (item.kind === 'type_registry') {
           get().setRegistry(item.types);
         } else if (item.kind === 'component') {
           get().updateComponents(item.components);
         } else if (item.kind === 'entity') {
           get().updateEntity(item.entity, item.mutation);
+        } else if (item.kind === 'resource') {
+          get().updateResource(item.resource, item.mutation);
         } else {
           console.log(item);
         }
@@ -131,6 +162,21 @@ export const createWsSlice: StateCreator<SharedSlice, [], [], WsSlice> = (
   },
 });
 
+// The server sends a full snapshot after connecting and after every `reset`,
+// anything kept from before would show entities that no longer exist.
+function emptyWorldState(): Partial<SharedSlice> {
+  return {
+    childParentMap: new Map(),
+    entities: new Map(),
+    entityNames: new Map(),
+    registry: new Map(),
+    componentNameToIdMap: new Map(),
+    components: new Map(),
+    resources: new Map(),
+    inspectingEntity: null,
+  };
+}
+
 export function parseWsURL(input: string): string | undefined {
   try {
     const url = new URL(input);
@@ -150,7 +196,16 @@ export type WsEvent = {
   };
 };
 
-type StreamEvent = TypeRegistryEvent | ComponentsEvent | EntityEvent;
+type StreamEvent =
+  | ResetEvent
+  | TypeRegistryEvent
+  | ComponentsEvent
+  | EntityEvent
+  | ResourceEvent;
+
+export type ResetEvent = {
+  kind: 'reset';
+};
 
 export type TypeRegistryEvent = {
   kind: 'type_registry';
@@ -176,3 +231,19 @@ export type EntityMutationChange = {
   removes: Array<[ComponentId, boolean]>;
 };
 export type EntityMutationRemove = { kind: 'remove' };
+
+export type ResourceEvent = {
+  kind: 'resource';
+  resource: ResourceId;
+  mutation: ResourceMutation;
+};
+
+export type ResourceMutation =
+  | {
+      kind: 'add';
+      name: string;
+      reflected: boolean;
+      value?: ResourceValue;
+    }
+  | { kind: 'change'; value: ResourceValue }
+  | { kind: 'remove' };